@@ -0,0 +1,309 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use ic_auth_plugin_client::{
+    Authenticated, HandshakeError, KeySelected, Plugin, PluginError, Transcript,
+};
+use ic_auth_plugin_types::{
+    AuthenticateError, AuthnMode, ProtocolVersion, SelectMode, SignEnvelopesRequest,
+};
+use ic_principal::Principal;
+use ic_transport_types::EnvelopeContent;
+use pico_args::Arguments;
+use serde_json::{Value, json};
+
+const HELP: &str = "\
+Drives an IC auth plugin by hand.
+
+Usage: ic-auth-plugin [OPTIONS] <PLUGIN> <COMMAND> [ARGS]
+
+Commands:
+    keys                      Lists the keys the plugin offers for selection
+    select <KEY>              Checks that the plugin accepts a key
+    authn-mode                Describes how the plugin will authenticate
+    authenticate              Authenticates, prompting for input if needed
+    principal                 Prints the principal and DER public key
+    sign-arbitrary <DATA>     Signs DATA as text, or as hex with --hex, or stdin if DATA is -
+    sign-delegation <PUBKEY>  Signs a delegation to a hex-encoded DER public key
+    sign-envelope <FILE>      Signs the envelope content (or array of them) in a JSON or CBOR file
+
+Options:
+    --key <NAME>            Key to select before running the command
+    --password <PASSWORD>   Password or PIN to use if the plugin asks for one [default: prompt]
+    --record <FILE>         Writes a transcript of the session, with secrets redacted
+    --hex                   sign-arbitrary: DATA is hex-encoded
+    --ttl <SECS>            sign-delegation: desired lifetime [default: 3600]
+    --canister <ID>         sign-delegation: canister to scope to, may be repeated [default: any]";
+
+// Wrong passwords are re-prompted for, and a stale `automatic` mode is re-queried, this many times.
+const AUTHN_ATTEMPTS: usize = 3;
+
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+struct Options {
+    key: Option<String>,
+    password: Option<String>,
+    record: Option<OsString>,
+    hex: bool,
+    ttl: u64,
+    canisters: Vec<Principal>,
+}
+
+enum Command {
+    Keys,
+    Select(String),
+    AuthnMode,
+    Authenticate,
+    Principal,
+    SignArbitrary(String),
+    SignDelegation(String),
+    SignEnvelope(OsString),
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    let mut args = Arguments::from_env();
+    if args.contains(["-h", "--help"]) {
+        println!("{HELP}");
+        return Ok(());
+    }
+    let options = Options {
+        key: args.opt_value_from_str("--key")?,
+        password: args.opt_value_from_str("--password")?,
+        record: args.opt_value_from_os_str("--record", |s| Ok::<_, anyhow::Error>(s.to_owned()))?,
+        hex: args.contains("--hex"),
+        ttl: args.opt_value_from_str("--ttl")?.unwrap_or(3600),
+        canisters: args.values_from_str("--canister")?,
+    };
+    let Some(program) = args.opt_free_from_os_str(|s| Ok::<_, anyhow::Error>(s.to_owned()))? else {
+        bail!("no plugin given\n\n{HELP}");
+    };
+    let Some(command): Option<String> = args.opt_free_from_str()? else {
+        bail!("no command given\n\n{HELP}");
+    };
+    let command = match &*command {
+        "keys" => Command::Keys,
+        "select" => Command::Select(args.free_from_str().context("missing KEY")?),
+        "authn-mode" => Command::AuthnMode,
+        "authenticate" => Command::Authenticate,
+        "principal" => Command::Principal,
+        "sign-arbitrary" => Command::SignArbitrary(args.free_from_str().context("missing DATA")?),
+        "sign-delegation" => {
+            Command::SignDelegation(args.free_from_str().context("missing PUBKEY")?)
+        }
+        "sign-envelope" => Command::SignEnvelope(
+            args.free_from_os_str(|s| Ok::<_, anyhow::Error>(s.to_owned()))
+                .context("missing FILE")?,
+        ),
+        command => bail!("unknown command {command}\n\n{HELP}"),
+    };
+    let rest = args.finish();
+    if !rest.is_empty() {
+        bail!("unexpected arguments {rest:?}");
+    }
+    let mut plugin = Plugin::open(&program)
+        .await
+        .context("failed to start plugin")?;
+    if let Some(path) = &options.record {
+        let transcript = Transcript::create(path)
+            .with_context(|| format!("failed to create {}", path.to_string_lossy()))?;
+        plugin.record(transcript);
+    }
+    run(plugin, command, options).await
+}
+
+async fn run(mut plugin: Plugin, command: Command, options: Options) -> Result<()> {
+    let key = match &command {
+        Command::Keys => {
+            match plugin.key_names().await? {
+                Some(keys) => {
+                    for key in &keys.keys {
+                        println!("{key}");
+                    }
+                    if !keys.exhaustive {
+                        eprintln!("(the plugin may accept keys not listed here)");
+                    }
+                }
+                None => eprintln!("The plugin does not list its keys."),
+            }
+            return shutdown(plugin).await;
+        }
+        Command::Select(key) => Some(key),
+        _ => options.key.as_ref(),
+    };
+    let mut plugin = match key {
+        Some(key) => plugin
+            .select_key(key)
+            .await
+            .map_err(|e| e.error)
+            .with_context(|| format!("failed to select key {key}"))?,
+        None if plugin.select_mode() == SelectMode::Required => {
+            bail!("the plugin requires a key to be selected with --key")
+        }
+        None => plugin.skip_key_selection().map_err(|e| e.error)?,
+    };
+    match command {
+        Command::Keys => unreachable!(),
+        Command::Select(key) => {
+            println!("Selected {key}.");
+            shutdown(plugin).await
+        }
+        Command::AuthnMode => {
+            let (mode, value) = plugin.authn_mode().await?;
+            println!("{}", mode_name(mode));
+            if let Some(value) = value {
+                println!("{value}");
+            }
+            shutdown(plugin).await
+        }
+        Command::Authenticate => {
+            let plugin = authenticate(plugin, options.password).await?;
+            println!("Authenticated.");
+            shutdown(plugin).await
+        }
+        Command::Principal => {
+            let mut plugin = authenticate(plugin, options.password).await?;
+            let public_key = plugin.public_key().await?;
+            println!("principal: {}", Principal::self_authenticating(&public_key));
+            println!("public-key-der: {}", hex::encode(public_key));
+            shutdown(plugin).await
+        }
+        Command::SignArbitrary(data) => {
+            let data = if data == "-" {
+                let mut data = Vec::new();
+                io::stdin().read_to_end(&mut data)?;
+                data
+            } else if options.hex {
+                hex::decode(data.trim()).context("DATA is not valid hex")?
+            } else {
+                data.into_bytes()
+            };
+            let mut plugin = authenticate(plugin, options.password).await?;
+            let signature = plugin.sign_arbitrary(&data).await?;
+            println!("signature: {}", hex::encode(signature));
+            shutdown(plugin).await
+        }
+        Command::SignDelegation(public_key) => {
+            let public_key = hex::decode(public_key.trim()).context("PUBKEY is not valid hex")?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            let desired_expiry = (now + Duration::from_secs(options.ttl)).as_nanos();
+            let canisters = (!options.canisters.is_empty()).then_some(&*options.canisters);
+            let mut plugin = authenticate(plugin, options.password).await?;
+            let (signature, expiry) = plugin
+                .sign_delegation(&public_key, desired_expiry, canisters)
+                .await?;
+            println!("signature: {}", hex::encode(signature));
+            println!("expiry: {expiry}");
+            if expiry < desired_expiry {
+                eprintln!(
+                    "(shortened by the plugin from {}s to {}s)",
+                    options.ttl,
+                    (expiry.saturating_sub(now.as_nanos()) / 1_000_000_000)
+                );
+            }
+            shutdown(plugin).await
+        }
+        Command::SignEnvelope(path) => {
+            let contents = read_contents(
+                &fs::read(&path)
+                    .with_context(|| format!("failed to read {}", path.to_string_lossy()))?,
+            )?;
+            let mut plugin = authenticate(plugin, options.password).await?;
+            let signatures = plugin.sign_envelopes(&contents).await?;
+            for (content, signature) in contents.iter().zip(signatures) {
+                println!("request-id: {}", hex::encode(*content.to_request_id()));
+                println!("signature: {}", hex::encode(signature));
+            }
+            shutdown(plugin).await
+        }
+    }
+}
+
+async fn authenticate(
+    mut plugin: Plugin<KeySelected>,
+    mut password: Option<String>,
+) -> Result<Plugin<Authenticated>> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let (mode, value) = plugin.authn_mode().await?;
+        // The host integrates whatever it can show in a terminal; windows are left to the plugin.
+        let (integrated, input) = match mode {
+            AuthnMode::Automatic => (Some(mode), None),
+            AuthnMode::Password => {
+                let password = match password.take() {
+                    Some(password) => password,
+                    None => rpassword::prompt_password("Password: ")?,
+                };
+                (Some(mode), Some(password))
+            }
+            AuthnMode::Url => {
+                let url = value.as_deref().unwrap_or("(the plugin gave no URL)");
+                eprintln!("Open {url} in a browser to authenticate.");
+                (Some(mode), None)
+            }
+            AuthnMode::Message => {
+                eprintln!(
+                    "{}",
+                    value.as_deref().unwrap_or("(the plugin gave no message)")
+                );
+                (Some(mode), None)
+            }
+            AuthnMode::Window => {
+                eprintln!("Authenticate in the plugin's window.");
+                (None, None)
+            }
+        };
+        match plugin.authenticate(integrated, input).await {
+            Ok(plugin) => return Ok(plugin),
+            Err(HandshakeError {
+                plugin: retry,
+                error: PluginError::Plugin(AuthenticateError::BadMode),
+            }) if attempts < AUTHN_ATTEMPTS => plugin = retry,
+            Err(HandshakeError {
+                plugin: retry,
+                error: PluginError::Plugin(AuthenticateError::BadAuthn { message }),
+            }) if attempts < AUTHN_ATTEMPTS => {
+                eprintln!("{message}");
+                plugin = retry;
+            }
+            Err(e) => return Err(e.error).context("failed to authenticate"),
+        }
+    }
+}
+
+// Accepts one envelope content or an array of them, as CBOR (the form the IC receives) or JSON.
+fn read_contents(file: &[u8]) -> Result<Vec<EnvelopeContent>> {
+    let Ok(json) = serde_json::from_slice::<Value>(file) else {
+        return match serde_cbor::from_slice(file) {
+            Ok(contents) => Ok(contents),
+            Err(_) => serde_cbor::from_slice(file)
+                .map(|content| vec![content])
+                .context("file is neither a JSON nor a CBOR envelope content"),
+        };
+    };
+    let contents = match json {
+        Value::Array(contents) => contents,
+        content => vec![content],
+    };
+    // Going through the request type decodes `read_state` path labels, which JSON holds as hex.
+    let req: SignEnvelopesRequest =
+        serde_json::from_value(json!({ "v": ProtocolVersion::V1, "contents": contents }))
+            .context("file is not a JSON envelope content")?;
+    Ok(req.contents.into_owned())
+}
+
+fn mode_name(mode: AuthnMode) -> String {
+    match serde_json::to_value(mode) {
+        Ok(Value::String(name)) => name,
+        _ => format!("{mode:?}"),
+    }
+}
+
+async fn shutdown<S>(plugin: Plugin<S>) -> Result<()> {
+    plugin.shutdown(SHUTDOWN_GRACE).await?;
+    Ok(())
+}
@@ -4,12 +4,14 @@ use ic_auth_plugin_types::AuthnMode;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut client = Plugin::open("target/debug/pkcs11-ic-auth-plugin").await?;
+    let client = Plugin::open("target/debug/pkcs11-ic-auth-plugin")
+        .await?
+        .skip_key_selection()?;
     println!("a");
-    client
+    let mut client = client
         .authenticate(Some(AuthnMode::Password), Some("1234".to_string()))
         .await?;
     println!("b");
-    let sig = client.sign_arbitrary(b"garbage!").await?;
+    let _sig = client.sign_arbitrary(b"garbage!").await?;
     Ok(())
 }
@@ -0,0 +1,371 @@
+use std::convert::Infallible;
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, BufWriter, Error as IoError, ErrorKind, Write};
+use std::marker::PhantomData;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::encoding::{Operation, Protocol, typestate_api};
+use crate::{EXIT_GRACE, Greeted, PluginError, Timeouts};
+
+// The blocking counterpart of the async `Plugin`, built on `std::process`. Responses are read on a
+// helper thread so that requests can still time out; as in the async client, a plugin that times out
+// is killed and unusable afterwards. The plugin is also killed if it is dropped while running.
+pub struct Plugin<S = Greeted> {
+    io: Box<PluginIo>,
+    protocol: Protocol,
+    _state: PhantomData<S>,
+}
+
+struct PluginIo {
+    child: Child,
+    stdin: Option<BufWriter<ChildStdin>>,
+    stdout: Receiver<io::Result<String>>,
+    stderr: Option<ChildStderr>,
+    timeouts: Timeouts,
+    unusable: bool,
+}
+
+impl Plugin {
+    pub fn open(program: impl AsRef<OsStr>) -> Result<Self, PluginError<Infallible>> {
+        Self::open_with_stderr(program, Stdio::inherit())
+    }
+
+    pub fn open_with_stderr(
+        program: impl AsRef<OsStr>,
+        stderr: impl Into<Stdio>,
+    ) -> Result<Self, PluginError<Infallible>> {
+        let mut child = Command::new(program)
+            .arg("--ic-auth-plugin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(stderr)
+            .spawn()?;
+        let stdout = match read_lines(child.stdout.take().unwrap()) {
+            Ok(stdout) => stdout,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e.into());
+            }
+        };
+        let mut io = Box::new(PluginIo {
+            stdin: Some(BufWriter::new(child.stdin.take().unwrap())),
+            stdout,
+            stderr: child.stderr.take(),
+            child,
+            timeouts: Timeouts::default(),
+            unusable: false,
+        });
+        let greeting = match io.stdout.recv() {
+            Ok(greeting) => greeting?,
+            Err(_) => {
+                return Err(match wait_timeout(&mut io.child, EXIT_GRACE) {
+                    Ok(Some(status)) => PluginError::Exited(status),
+                    _ => PluginError::Io(IoError::from(ErrorKind::UnexpectedEof)),
+                });
+            }
+        };
+        match Protocol::greet(&greeting) {
+            Ok(protocol) => Ok(Self {
+                io,
+                protocol,
+                _state: PhantomData,
+            }),
+            Err(PluginError::Aborted { message, .. }) => {
+                io.stdin = None;
+                // A plugin that aborts is expected to exit, but one that lingers must not block
+                // `open`.
+                let status = match wait_timeout(&mut io.child, EXIT_GRACE) {
+                    Ok(Some(status)) => Some(status),
+                    _ => {
+                        let _ = io.child.kill();
+                        io.child.wait().ok()
+                    }
+                };
+                Err(PluginError::Aborted { message, status })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+typestate_api!(;);
+
+impl<S> Plugin<S> {
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.io.stderr.take()
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.io.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.io.timeouts = timeouts;
+    }
+
+    pub fn is_usable(&self) -> bool {
+        !self.io.unusable
+    }
+
+    pub fn id(&self) -> u32 {
+        self.io.child.id()
+    }
+
+    pub fn exit_status(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.io.child.try_wait()
+    }
+
+    // Closes the plugin's stdin, its signal to shut down gracefully, and kills it if it has not
+    // exited within `grace`.
+    pub fn shutdown(self, grace: Duration) -> io::Result<ExitStatus> {
+        let mut io = self.io;
+        io.stdin = None;
+        match wait_timeout(&mut io.child, grace)? {
+            Some(status) => Ok(status),
+            None => {
+                io.child.kill()?;
+                io.child.wait()
+            }
+        }
+    }
+
+    fn call<O: Operation>(&mut self, op: O) -> Result<O::Output, PluginError<O::Error>> {
+        let action = op.action();
+        if !self.is_usable() {
+            return Err(PluginError::Unusable);
+        }
+        let req = self.protocol.request(&op)?;
+        let resp = match self.io.exchange(&req, self.io.timeouts.for_action(action)) {
+            Ok(resp) => resp,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                self.io.poison();
+                return Err(PluginError::Timeout(action));
+            }
+            Err(e) => return Err(self.io.exit_error(e)),
+        };
+        self.protocol.response::<O>(action, &resp)
+    }
+}
+
+impl PluginIo {
+    fn exchange(&mut self, line: &str, timeout: Option<Duration>) -> io::Result<String> {
+        self.writeln(line)?;
+        self.readln(timeout)
+    }
+
+    fn writeln(&mut self, line: &str) -> io::Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| IoError::from(ErrorKind::BrokenPipe))?;
+        stdin.write_all(line.as_bytes())?;
+        stdin.write_all(b"\n")?;
+        stdin.flush()
+    }
+
+    // Only a request timeout produces `ErrorKind::TimedOut`.
+    fn readln(&mut self, timeout: Option<Duration>) -> io::Result<String> {
+        match timeout {
+            Some(timeout) => self.stdout.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => ErrorKind::TimedOut,
+                RecvTimeoutError::Disconnected => ErrorKind::UnexpectedEof,
+            })?,
+            None => self.stdout.recv().map_err(|_| ErrorKind::UnexpectedEof)?,
+        }
+    }
+
+    // A closed pipe usually means the plugin exited; report its status if it is available promptly.
+    fn exit_error<E>(&mut self, e: IoError) -> PluginError<E> {
+        if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe) {
+            if let Ok(Some(status)) = wait_timeout(&mut self.child, EXIT_GRACE) {
+                return PluginError::Exited(status);
+            }
+        }
+        PluginError::Io(e)
+    }
+
+    fn poison(&mut self) {
+        self.unusable = true;
+        let _ = self.child.kill();
+    }
+}
+
+impl Drop for PluginIo {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+fn read_lines(stdout: ChildStdout) -> io::Result<Receiver<io::Result<String>>> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("ic-auth-plugin-stdout".into())
+        .spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let failed = line.is_err();
+                if tx.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        })?;
+    Ok(rx)
+}
+
+fn wait_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(all(test, unix, feature = "testing"))]
+mod tests {
+    use ic_auth_plugin_types::{
+        Action, AuthenticateError, AuthenticateResponse, AuthnMode, DescribeAuthnModeError,
+        DescribeAuthnModeResponse, GetPublicKeyError, GetPublicKeyResponse, Greeting,
+        KeySelectError, KeySelectResponse, ListSelectableKeysError, ListSelectableKeysResponse,
+        ProtocolVersion, SelectMode, SignArbitraryDataError, SignArbitraryDataResponse,
+    };
+
+    use super::*;
+    use crate::{MockKey, ScriptPlugin};
+
+    fn exiting_plugin(name: &str) -> ScriptPlugin {
+        ScriptPlugin::canned(
+            name,
+            &Greeting {
+                v: vec![ProtocolVersion::V1],
+                select: None,
+                abort: None,
+            },
+        )
+    }
+
+    #[test]
+    fn runs_the_handshake() {
+        let key = MockKey::from_seed("a", [1; 32]);
+        let script = ScriptPlugin::canned(
+            "blocking-handshake",
+            &Greeting {
+                v: vec![ProtocolVersion::V1],
+                select: Some(SelectMode::Supported),
+                abort: None,
+            },
+        );
+        script.write(
+            "list-selectable-keys",
+            &Ok::<_, ListSelectableKeysError>(ListSelectableKeysResponse {
+                keys: vec!["a".into()],
+                exhaustive: true,
+            }),
+        );
+        script.write("select-key", &Ok::<_, KeySelectError>(KeySelectResponse {}));
+        script.write(
+            "describe-authn-mode",
+            &Ok::<_, DescribeAuthnModeError>(DescribeAuthnModeResponse {
+                mode: AuthnMode::Password,
+                value: None,
+            }),
+        );
+        script.write(
+            "authenticate",
+            &Ok::<_, AuthenticateError>(AuthenticateResponse {}),
+        );
+        script.write(
+            "get-public-key",
+            &Ok::<_, GetPublicKeyError>(GetPublicKeyResponse {
+                public_key_der: key.public_key_der().into(),
+            }),
+        );
+        script.write(
+            "sign-arbitrary-data",
+            &Ok::<_, SignArbitraryDataError>(SignArbitraryDataResponse {
+                signature: key.sign(b"data").into(),
+            }),
+        );
+        let mut plugin = Plugin::open(script.path()).unwrap();
+        assert_eq!(plugin.key_names().unwrap().unwrap().keys, ["a"]);
+        let mut plugin = plugin.select_key("a").unwrap();
+        assert_eq!(plugin.authn_mode().unwrap(), (AuthnMode::Password, None));
+        let mut plugin = plugin
+            .authenticate(Some(AuthnMode::Password), Some("hunter2".into()))
+            .unwrap();
+        assert_eq!(plugin.public_key().unwrap(), key.public_key_der());
+        assert_eq!(plugin.sign_arbitrary(b"data").unwrap(), key.sign(b"data"));
+        assert_eq!(
+            plugin.shutdown(Duration::from_secs(10)).unwrap().code(),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn timed_out_requests_poison_the_plugin() {
+        let script = exiting_plugin("blocking-timeout");
+        script.write(
+            "authenticate",
+            &Ok::<_, AuthenticateError>(AuthenticateResponse {}),
+        );
+        let mut plugin = Plugin::open(script.path())
+            .unwrap()
+            .skip_key_selection()
+            .unwrap()
+            .authenticate(None, None)
+            .unwrap();
+        plugin.set_timeouts(Timeouts {
+            signing: Some(Duration::from_millis(100)),
+            ..Timeouts::default()
+        });
+        assert!(matches!(
+            plugin.sign_arbitrary(b"data"),
+            Err(PluginError::Timeout(Action::SignArbitraryData))
+        ));
+        assert!(!plugin.is_usable());
+        assert!(matches!(plugin.public_key(), Err(PluginError::Unusable)));
+        // The plugin was killed when it timed out.
+        let status = wait_timeout(&mut plugin.io.child, Duration::from_secs(10)).unwrap();
+        assert!(status.is_some_and(|status| !status.success()));
+    }
+
+    #[test]
+    fn dropping_kills_the_plugin() {
+        let script = exiting_plugin("blocking-drop");
+        script.write("linger", &());
+        let plugin = Plugin::open(script.path()).unwrap();
+        let pid = plugin.id().to_string();
+        drop(plugin);
+        let alive = Command::new("kill").args(["-0", &pid]).status().unwrap();
+        assert!(!alive.success());
+    }
+
+    #[test]
+    fn shutdown_reports_the_exit_status() {
+        let script = exiting_plugin("blocking-shutdown");
+        let mut plugin = Plugin::open(script.path()).unwrap();
+        assert!(plugin.exit_status().unwrap().is_none());
+        let status = plugin.shutdown(Duration::from_secs(10)).unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn shutdown_kills_plugins_that_ignore_eof() {
+        let script = exiting_plugin("blocking-shutdown-linger");
+        script.write("linger", &());
+        let plugin = Plugin::open(script.path()).unwrap();
+        let status = plugin.shutdown(Duration::from_millis(100)).unwrap();
+        assert!(!status.success());
+        assert_eq!(status.code(), None);
+    }
+}
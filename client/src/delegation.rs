@@ -0,0 +1,117 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_consensus::SigningKey;
+use ic_agent::Identity;
+use ic_agent::identity::{BasicIdentity, DelegatedIdentity, DelegationError};
+use ic_auth_plugin_types::{GetPublicKeyError, SignDelegationError};
+use ic_principal::Principal;
+use ic_transport_types::{Delegation, SignedDelegation};
+use thiserror::Error;
+
+use crate::{Authenticated, Plugin, PluginError, SupervisedPlugin, SupervisorError, ThreadExited};
+
+#[derive(Error, Debug)]
+pub enum DelegateError {
+    #[error("failed to get public key: {0}")]
+    PublicKey(#[from] PluginError<GetPublicKeyError>),
+    #[error("failed to sign delegation: {0}")]
+    Sign(#[from] SupervisorError<SignDelegationError>),
+    #[error("plugin returned out-of-range expiry {0}")]
+    Expiry(u128),
+    #[error("plugin returned an invalid delegation: {0}")]
+    Delegation(#[from] DelegationError),
+    #[error(transparent)]
+    ThreadExited(#[from] ThreadExited),
+}
+
+impl DelegateError {
+    pub fn rejection(&self) -> Option<&SignDelegationError> {
+        match self {
+            Self::Sign(SupervisorError::Plugin(PluginError::Plugin(err))) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<PluginError<SignDelegationError>> for DelegateError {
+    fn from(err: PluginError<SignDelegationError>) -> Self {
+        Self::Sign(err.into())
+    }
+}
+
+// `Identity::sign_delegation` has no way to report that the plugin shortened the expiry, so instead
+// the session key is generated here and the plugin's chosen expiry is put into the delegation.
+impl Plugin<Authenticated> {
+    pub async fn delegated_identity(
+        &mut self,
+        desired_expiry: u64,
+        desired_canisters: Option<&[Principal]>,
+    ) -> Result<DelegatedIdentity, DelegateError> {
+        let from_key = self.public_key().await?;
+        let session = session_identity();
+        let session_key = session.public_key().unwrap();
+        let (signature, expiry) = self
+            .sign_delegation(&session_key, desired_expiry.into(), desired_canisters)
+            .await?;
+        delegated_identity(from_key, session, signature, expiry, desired_canisters)
+    }
+}
+
+impl SupervisedPlugin {
+    pub async fn delegated_identity(
+        &mut self,
+        desired_expiry: u64,
+        desired_canisters: Option<&[Principal]>,
+    ) -> Result<DelegatedIdentity, DelegateError> {
+        let session = session_identity();
+        let session_key = session.public_key().unwrap();
+        let (signature, expiry) = self
+            .sign_delegation(&session_key, desired_expiry.into(), desired_canisters)
+            .await?;
+        delegated_identity(
+            self.public_key().to_vec(),
+            session,
+            signature,
+            expiry,
+            desired_canisters,
+        )
+    }
+}
+
+pub(crate) fn session_identity() -> BasicIdentity {
+    BasicIdentity::from_signing_key(SigningKey::new(rand::thread_rng()))
+}
+
+pub(crate) fn delegated_identity(
+    from_key: Vec<u8>,
+    session: BasicIdentity,
+    signature: Vec<u8>,
+    expiry: u128,
+    canisters: Option<&[Principal]>,
+) -> Result<DelegatedIdentity, DelegateError> {
+    // An already expired delegation would make every signature made with the session key invalid.
+    let expiration = u64::try_from(expiry)
+        .ok()
+        .filter(|&expiration| expiration > now())
+        .ok_or(DelegateError::Expiry(expiry))?;
+    let delegation = SignedDelegation {
+        delegation: Delegation {
+            pubkey: session.public_key().unwrap(),
+            expiration,
+            targets: canisters.map(<[Principal]>::to_vec),
+        },
+        signature,
+    };
+    Ok(DelegatedIdentity::new(
+        from_key,
+        Box::new(session),
+        vec![delegation],
+    )?)
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
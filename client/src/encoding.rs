@@ -0,0 +1,576 @@
+use std::borrow::Cow;
+use std::convert::Infallible;
+
+use ic_auth_plugin_types::{
+    Action, AuthenticateError, AuthenticateRequest, AuthenticateResponse, AuthnMode,
+    DescribeAuthnModeError, DescribeAuthnModeRequest, DescribeAuthnModeResponse, ExtensionError,
+    ExtensionRequest, GetPublicKeyError, GetPublicKeyRequest, GetPublicKeyResponse, Greeting,
+    KeySelectError, KeySelectRequest, KeySelectResponse, ListSelectableKeysError,
+    ListSelectableKeysRequest, ListSelectableKeysResponse, Phase, ProtocolState, ProtocolVersion,
+    Request, SelectMode, SignArbitraryDataError, SignArbitraryDataRequest,
+    SignArbitraryDataResponse, SignDelegationError, SignDelegationRequest, SignDelegationResponse,
+    SignEnvelopesError, SignEnvelopesRequest, SignEnvelopesResponse,
+};
+use ic_principal::Principal;
+use ic_transport_types::EnvelopeContent;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::PluginError;
+
+// Each request a host can make, with its response and error types. The async and blocking clients
+// only differ in how the lines are exchanged, so both encode requests and decode responses here.
+pub(crate) trait Operation {
+    type Response: DeserializeOwned;
+    type Error: DeserializeOwned;
+    type Output;
+    fn action(&self) -> Action;
+    fn encode(&self) -> serde_json::Result<String>;
+    fn output(resp: Self::Response) -> Self::Output;
+}
+
+fn decode<O: Operation>(line: &str) -> serde_json::Result<Result<O::Output, O::Error>> {
+    let resp: Result<O::Response, O::Error> = serde_json::from_str(line)?;
+    Ok(resp.map(O::output))
+}
+
+// What both clients track about a plugin besides its I/O: the versions it greeted with, and the
+// handshake phase that decides which requests may be made next.
+pub(crate) struct Protocol {
+    state: ProtocolState,
+    versions: Vec<ProtocolVersion>,
+}
+
+impl Protocol {
+    // An aborting greeting is reported without an exit status, for the caller to fill in once the
+    // plugin has exited.
+    pub(crate) fn greet(line: &str) -> Result<Self, PluginError<Infallible>> {
+        let greeting: Greeting = serde_json::from_str(line)?;
+        if let Some(message) = greeting.abort {
+            return Err(PluginError::Aborted {
+                message,
+                status: None,
+            });
+        }
+        if !greeting.supports(&ProtocolVersion::V1) {
+            return Err(PluginError::Incompatible);
+        }
+        Ok(Self {
+            state: ProtocolState::from_greeting(&greeting),
+            versions: greeting.v,
+        })
+    }
+
+    pub(crate) fn select_mode(&self) -> SelectMode {
+        self.state.select_mode()
+    }
+
+    pub(crate) fn phase(&self) -> Phase {
+        self.state.phase()
+    }
+
+    pub(crate) fn versions(&self) -> &[ProtocolVersion] {
+        &self.versions
+    }
+
+    pub(crate) fn supports_extension(&self, extension: &str) -> bool {
+        self.versions
+            .iter()
+            .any(|v| v.extension_name() == Some(extension))
+    }
+
+    // Encodes `op` if the handshake allows it now.
+    pub(crate) fn request<O: Operation>(&self, op: &O) -> Result<String, PluginError<O::Error>> {
+        self.state.check(op.action())?;
+        Ok(op.encode()?)
+    }
+
+    pub(crate) fn response<O: Operation>(
+        &mut self,
+        action: Action,
+        line: &str,
+    ) -> Result<O::Output, PluginError<O::Error>> {
+        let resp = decode::<O>(line)?;
+        self.state.complete(action, resp.is_ok());
+        resp.map_err(PluginError::Plugin)
+    }
+}
+
+// The typestate API, shared by the async and blocking `Plugin`s. Each provides `io`, `protocol` and
+// `_state` fields and a `call` method, and invokes this with `async; .await` or with nothing.
+macro_rules! typestate_api {
+    ($($async:ident)?; $($await:tt)*) => {
+        impl Plugin<$crate::Greeted> {
+            pub $($async)? fn select_key(
+                mut self,
+                key: &str,
+            ) -> Result<
+                Plugin<$crate::KeySelected>,
+                $crate::HandshakeError<Self, $crate::types::KeySelectError>,
+            > {
+                match self.call($crate::encoding::SelectKey(key)) $($await)* {
+                    Ok(()) => Ok(self.into_state()),
+                    Err(error) => Err($crate::HandshakeError {
+                        plugin: self,
+                        error,
+                    }),
+                }
+            }
+
+            pub fn skip_key_selection(
+                self,
+            ) -> Result<
+                Plugin<$crate::KeySelected>,
+                $crate::HandshakeError<Self, ::std::convert::Infallible>,
+            > {
+                if self.select_mode() == $crate::types::SelectMode::Required {
+                    return Err($crate::HandshakeError {
+                        plugin: self,
+                        error: $crate::PluginError::Protocol(
+                            $crate::types::ProtocolViolation::SelectRequired(
+                                $crate::types::Action::Authenticate,
+                            ),
+                        ),
+                    });
+                }
+                Ok(self.into_state())
+            }
+        }
+
+        impl Plugin<$crate::KeySelected> {
+            pub $($async)? fn authn_mode(
+                &mut self,
+            ) -> Result<
+                ($crate::types::AuthnMode, Option<String>),
+                $crate::PluginError<$crate::types::DescribeAuthnModeError>,
+            > {
+                self.call($crate::encoding::DescribeAuthnMode) $($await)*
+            }
+
+            pub $($async)? fn public_key(
+                &mut self,
+            ) -> Result<Vec<u8>, $crate::PluginError<$crate::types::GetPublicKeyError>> {
+                self.call($crate::encoding::PublicKey) $($await)*
+            }
+
+            pub $($async)? fn authenticate(
+                mut self,
+                integrated_mode: Option<$crate::types::AuthnMode>,
+                integrated_value: Option<String>,
+            ) -> Result<
+                Plugin<$crate::Authenticated>,
+                $crate::HandshakeError<Self, $crate::types::AuthenticateError>,
+            > {
+                let op = $crate::encoding::Authenticate {
+                    integrated_mode,
+                    integrated_value,
+                };
+                match self.call(op) $($await)* {
+                    Ok(()) => Ok(self.into_state()),
+                    Err(error) => Err($crate::HandshakeError {
+                        plugin: self,
+                        error,
+                    }),
+                }
+            }
+        }
+
+        impl Plugin<$crate::Authenticated> {
+            pub $($async)? fn public_key(
+                &mut self,
+            ) -> Result<Vec<u8>, $crate::PluginError<$crate::types::GetPublicKeyError>> {
+                self.call($crate::encoding::PublicKey) $($await)*
+            }
+
+            pub $($async)? fn sign_envelopes(
+                &mut self,
+                envelopes: &[::ic_transport_types::EnvelopeContent],
+            ) -> Result<Vec<Vec<u8>>, $crate::PluginError<$crate::types::SignEnvelopesError>> {
+                self.call($crate::encoding::SignEnvelopes(envelopes)) $($await)*
+            }
+
+            pub $($async)? fn sign_delegation(
+                &mut self,
+                public_key_der: &[u8],
+                desired_expiry: u128,
+                desired_canisters: Option<&[::ic_principal::Principal]>,
+            ) -> Result<(Vec<u8>, u128), $crate::PluginError<$crate::types::SignDelegationError>>
+            {
+                self.call($crate::encoding::SignDelegation {
+                    public_key_der,
+                    desired_expiry,
+                    desired_canisters,
+                })
+                $($await)*
+            }
+
+            pub $($async)? fn sign_arbitrary(
+                &mut self,
+                data: &[u8],
+            ) -> Result<Vec<u8>, $crate::PluginError<$crate::types::SignArbitraryDataError>> {
+                self.call($crate::encoding::SignArbitrary(data)) $($await)*
+            }
+
+            pub $($async)? fn extension_request(
+                &mut self,
+                extension: &str,
+                action: &str,
+                payload: ::serde_json::Map<String, ::serde_json::Value>,
+            ) -> Result<
+                ::serde_json::Map<String, ::serde_json::Value>,
+                $crate::PluginError<$crate::types::ExtensionError>,
+            > {
+                self.do_extension_request(extension, action, payload) $($await)*
+            }
+        }
+
+        impl Plugin<$crate::Dynamic> {
+            pub fn into_authenticated(self) -> Result<Plugin<$crate::Authenticated>, Self> {
+                if self.phase() == $crate::types::Phase::Authenticated {
+                    Ok(self.into_state())
+                } else {
+                    Err(self)
+                }
+            }
+
+            pub $($async)? fn select_key(
+                &mut self,
+                key: &str,
+            ) -> Result<(), $crate::PluginError<$crate::types::KeySelectError>> {
+                self.call($crate::encoding::SelectKey(key)) $($await)*
+            }
+
+            pub $($async)? fn authn_mode(
+                &mut self,
+            ) -> Result<
+                ($crate::types::AuthnMode, Option<String>),
+                $crate::PluginError<$crate::types::DescribeAuthnModeError>,
+            > {
+                self.call($crate::encoding::DescribeAuthnMode) $($await)*
+            }
+
+            pub $($async)? fn authenticate(
+                &mut self,
+                integrated_mode: Option<$crate::types::AuthnMode>,
+                integrated_value: Option<String>,
+            ) -> Result<(), $crate::PluginError<$crate::types::AuthenticateError>> {
+                self.call($crate::encoding::Authenticate {
+                    integrated_mode,
+                    integrated_value,
+                })
+                $($await)*
+            }
+
+            pub $($async)? fn public_key(
+                &mut self,
+            ) -> Result<Vec<u8>, $crate::PluginError<$crate::types::GetPublicKeyError>> {
+                self.call($crate::encoding::PublicKey) $($await)*
+            }
+
+            pub $($async)? fn sign_envelopes(
+                &mut self,
+                envelopes: &[::ic_transport_types::EnvelopeContent],
+            ) -> Result<Vec<Vec<u8>>, $crate::PluginError<$crate::types::SignEnvelopesError>> {
+                self.call($crate::encoding::SignEnvelopes(envelopes)) $($await)*
+            }
+
+            pub $($async)? fn sign_delegation(
+                &mut self,
+                public_key_der: &[u8],
+                desired_expiry: u128,
+                desired_canisters: Option<&[::ic_principal::Principal]>,
+            ) -> Result<(Vec<u8>, u128), $crate::PluginError<$crate::types::SignDelegationError>>
+            {
+                self.call($crate::encoding::SignDelegation {
+                    public_key_der,
+                    desired_expiry,
+                    desired_canisters,
+                })
+                $($await)*
+            }
+
+            pub $($async)? fn sign_arbitrary(
+                &mut self,
+                data: &[u8],
+            ) -> Result<Vec<u8>, $crate::PluginError<$crate::types::SignArbitraryDataError>> {
+                self.call($crate::encoding::SignArbitrary(data)) $($await)*
+            }
+
+            pub $($async)? fn extension_request(
+                &mut self,
+                extension: &str,
+                action: &str,
+                payload: ::serde_json::Map<String, ::serde_json::Value>,
+            ) -> Result<
+                ::serde_json::Map<String, ::serde_json::Value>,
+                $crate::PluginError<$crate::types::ExtensionError>,
+            > {
+                self.do_extension_request(extension, action, payload) $($await)*
+            }
+        }
+
+        impl<S> Plugin<S> {
+            pub fn select_mode(&self) -> $crate::types::SelectMode {
+                self.protocol.select_mode()
+            }
+
+            pub fn phase(&self) -> $crate::types::Phase {
+                self.protocol.phase()
+            }
+
+            pub fn versions(&self) -> &[$crate::types::ProtocolVersion] {
+                self.protocol.versions()
+            }
+
+            pub fn supports_extension(&self, extension: &str) -> bool {
+                self.protocol.supports_extension(extension)
+            }
+
+            pub fn into_dynamic(self) -> Plugin<$crate::Dynamic> {
+                self.into_state()
+            }
+
+            fn into_state<T>(self) -> Plugin<T> {
+                Plugin {
+                    io: self.io,
+                    protocol: self.protocol,
+                    _state: ::std::marker::PhantomData,
+                }
+            }
+
+            pub $($async)? fn key_names(
+                &mut self,
+            ) -> Result<
+                Option<$crate::types::ListSelectableKeysResponse>,
+                $crate::PluginError<$crate::types::ListSelectableKeysError>,
+            > {
+                match self.call($crate::encoding::KeyNames) $($await)* {
+                    Ok(keys) => Ok(Some(keys)),
+                    Err($crate::PluginError::Plugin(
+                        $crate::types::ListSelectableKeysError::Unsupported,
+                    )) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+
+            $($async)? fn do_extension_request(
+                &mut self,
+                extension: &str,
+                action: &str,
+                payload: ::serde_json::Map<String, ::serde_json::Value>,
+            ) -> Result<
+                ::serde_json::Map<String, ::serde_json::Value>,
+                $crate::PluginError<$crate::types::ExtensionError>,
+            > {
+                if !self.supports_extension(extension) {
+                    return Err($crate::PluginError::Incompatible);
+                }
+                self.call($crate::encoding::Extension {
+                    extension,
+                    action,
+                    payload,
+                })
+                $($await)*
+            }
+        }
+    };
+}
+
+pub(crate) use typestate_api;
+
+fn encode_request(req: Request<'_>) -> serde_json::Result<String> {
+    serde_json::to_string(&req)
+}
+
+pub(crate) struct KeyNames;
+
+impl Operation for KeyNames {
+    type Response = ListSelectableKeysResponse;
+    type Error = ListSelectableKeysError;
+    type Output = ListSelectableKeysResponse;
+    fn action(&self) -> Action {
+        Action::ListSelectableKeys
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::ListSelectableKeys(ListSelectableKeysRequest {
+            v: ProtocolVersion::V1,
+        }))
+    }
+    fn output(resp: Self::Response) -> Self::Output {
+        resp
+    }
+}
+
+pub(crate) struct SelectKey<'a>(pub &'a str);
+
+impl Operation for SelectKey<'_> {
+    type Response = KeySelectResponse;
+    type Error = KeySelectError;
+    type Output = ();
+    fn action(&self) -> Action {
+        Action::KeySelect
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::KeySelect(KeySelectRequest {
+            v: ProtocolVersion::V1,
+            key: self.0.into(),
+        }))
+    }
+    fn output(KeySelectResponse {}: Self::Response) -> Self::Output {}
+}
+
+pub(crate) struct DescribeAuthnMode;
+
+impl Operation for DescribeAuthnMode {
+    type Response = DescribeAuthnModeResponse;
+    type Error = DescribeAuthnModeError;
+    type Output = (AuthnMode, Option<String>);
+    fn action(&self) -> Action {
+        Action::DescribeAuthnMode
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::DescribeAuthnMode(DescribeAuthnModeRequest {
+            v: ProtocolVersion::V1,
+        }))
+    }
+    fn output(resp: Self::Response) -> Self::Output {
+        (resp.mode, resp.value)
+    }
+}
+
+pub(crate) struct Authenticate {
+    pub integrated_mode: Option<AuthnMode>,
+    pub integrated_value: Option<String>,
+}
+
+impl Operation for Authenticate {
+    type Response = AuthenticateResponse;
+    type Error = AuthenticateError;
+    type Output = ();
+    fn action(&self) -> Action {
+        Action::Authenticate
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::Authenticate(AuthenticateRequest {
+            integrated: self.integrated_mode,
+            value: self.integrated_value.as_deref().map(Cow::from),
+            v: ProtocolVersion::V1,
+        }))
+    }
+    fn output(AuthenticateResponse {}: Self::Response) -> Self::Output {}
+}
+
+pub(crate) struct PublicKey;
+
+impl Operation for PublicKey {
+    type Response = GetPublicKeyResponse<'static>;
+    type Error = GetPublicKeyError;
+    type Output = Vec<u8>;
+    fn action(&self) -> Action {
+        Action::GetPublicKey
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::GetPublicKey(GetPublicKeyRequest {
+            v: ProtocolVersion::V1,
+        }))
+    }
+    fn output(resp: Self::Response) -> Self::Output {
+        resp.public_key_der.into_owned()
+    }
+}
+
+pub(crate) struct SignEnvelopes<'a>(pub &'a [EnvelopeContent]);
+
+impl Operation for SignEnvelopes<'_> {
+    type Response = SignEnvelopesResponse<'static>;
+    type Error = SignEnvelopesError;
+    type Output = Vec<Vec<u8>>;
+    fn action(&self) -> Action {
+        Action::SignEnvelopes
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::SignEnvelopes(SignEnvelopesRequest {
+            v: ProtocolVersion::V1,
+            contents: self.0.into(),
+        }))
+    }
+    fn output(resp: Self::Response) -> Self::Output {
+        #[allow(clippy::unnecessary_to_owned)] // false positive, the first into_owned is no-op
+        resp.signatures
+            .into_owned()
+            .into_iter()
+            .map(|s| s.into_owned())
+            .collect()
+    }
+}
+
+pub(crate) struct SignDelegation<'a> {
+    pub public_key_der: &'a [u8],
+    pub desired_expiry: u128,
+    pub desired_canisters: Option<&'a [Principal]>,
+}
+
+impl Operation for SignDelegation<'_> {
+    type Response = SignDelegationResponse<'static>;
+    type Error = SignDelegationError;
+    type Output = (Vec<u8>, u128);
+    fn action(&self) -> Action {
+        Action::SignDelegation
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::SignDelegation(SignDelegationRequest {
+            v: ProtocolVersion::V1,
+            public_key_der: self.public_key_der.into(),
+            desired_expiry: self.desired_expiry,
+            desired_canisters: self.desired_canisters.map(Into::into),
+        }))
+    }
+    fn output(resp: Self::Response) -> Self::Output {
+        (resp.signature.into_owned(), resp.expiry)
+    }
+}
+
+pub(crate) struct SignArbitrary<'a>(pub &'a [u8]);
+
+impl Operation for SignArbitrary<'_> {
+    type Response = SignArbitraryDataResponse<'static>;
+    type Error = SignArbitraryDataError;
+    type Output = Vec<u8>;
+    fn action(&self) -> Action {
+        Action::SignArbitraryData
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        encode_request(Request::SignArbitraryData(SignArbitraryDataRequest {
+            v: ProtocolVersion::V1,
+            data: self.0.into(),
+        }))
+    }
+    fn output(resp: Self::Response) -> Self::Output {
+        resp.signature.into_owned()
+    }
+}
+
+pub(crate) struct Extension<'a> {
+    pub extension: &'a str,
+    pub action: &'a str,
+    pub payload: Map<String, Value>,
+}
+
+impl Operation for Extension<'_> {
+    type Response = Map<String, Value>;
+    type Error = ExtensionError;
+    type Output = Map<String, Value>;
+    fn action(&self) -> Action {
+        Action::Extension
+    }
+    fn encode(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&ExtensionRequest {
+            v: ProtocolVersion::extension(self.extension),
+            action: self.action.into(),
+            payload: self.payload.clone(),
+        })
+    }
+    fn output(resp: Self::Response) -> Self::Output {
+        resp
+    }
+}
@@ -1,76 +1,403 @@
-use std::{slice, sync::Mutex};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
 
-use futures::executor::block_on;
+use futures::channel::oneshot;
+use ic_agent::hash_tree::Label;
+use ic_agent::identity::DelegatedIdentity;
 use ic_agent::{Identity, Signature};
+use ic_auth_plugin_types::{AuthnMode, SignEnvelopesError};
 use ic_principal::Principal;
-use ic_transport_types::{Delegation, EnvelopeContent};
+use ic_transport_types::{Delegation, EnvelopeContent, RequestId};
+use thiserror::Error;
+use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
 
-use crate::Plugin;
+use crate::delegation::now;
+use crate::{
+    Authenticated, AuthnInput, DelegateError, LaunchError, Plugin, PluginError, SupervisedPlugin,
+    SupervisorError, Timeouts,
+};
 
+type Job = Box<dyn FnOnce(&mut SupervisedPlugin, &Runtime) + Send>;
+
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+// The plugin is driven by its own thread and current-thread runtime, so that the synchronous
+// `Identity` methods can block on it from any context (including a current-thread runtime that would
+// otherwise have to drive the plugin's pipes itself). Requests from all threads are queued and
+// handled in order.
 pub struct PluginIdentity {
-    plugin: Mutex<Plugin>,
+    jobs: Sender<Job>,
+    // Fetched at launch. The supervisor refuses to restart the plugin with a different key, so these
+    // never go stale.
+    public_key: Vec<u8>,
+    principal: Principal,
+    presigned: Mutex<HashMap<RequestId, Presigned>>,
+}
+
+// SPEC.md lets an update be confirmed together with the `read_state` request that polls for its
+// status. Each call is signed alongside a `read_state` for its request ID with the same sender and
+// expiry (which ic-agent also produces for polls made within the same expiry rounding window), and
+// the second signature is kept until it expires.
+struct Presigned {
+    ingress_expiry: u64,
+    signature: Vec<u8>,
+}
+
+// Every request to a `PluginIdentity` fails with this once its plugin thread is gone, which happens
+// if the plugin panics.
+#[derive(Error, Debug, Clone, Copy, Eq, PartialEq)]
+#[error("plugin thread has exited")]
+pub struct ThreadExited;
+
+#[derive(Error, Debug)]
+enum SignError {
+    #[error(transparent)]
+    Plugin(#[from] SupervisorError<SignEnvelopesError>),
+    #[error(transparent)]
+    ThreadExited(#[from] ThreadExited),
 }
 
 impl PluginIdentity {
-    pub(crate) fn new(plugin: Plugin) -> Self {
+    pub async fn launch(
+        program: impl AsRef<OsStr>,
+        key: Option<&str>,
+        authn: AuthnInput,
+        prompt: impl FnMut(AuthnMode, Option<&str>) -> Option<AuthnInput> + Send + 'static,
+    ) -> Result<Self, LaunchError> {
+        Self::launch_with_timeouts(program, key, authn, prompt, Timeouts::default()).await
+    }
+
+    pub async fn launch_with_timeouts(
+        program: impl AsRef<OsStr>,
+        key: Option<&str>,
+        authn: AuthnInput,
+        prompt: impl FnMut(AuthnMode, Option<&str>) -> Option<AuthnInput> + Send + 'static,
+        timeouts: Timeouts,
+    ) -> Result<Self, LaunchError> {
+        let program = program.as_ref().to_owned();
+        let key = key.map(String::from);
+        let rt = runtime()?;
+        let (jobs, rx) = mpsc::channel();
+        let (ready, launched) = oneshot::channel();
+        spawn(move || {
+            let launch = SupervisedPlugin::launch_with_timeouts(
+                &program,
+                key.as_deref(),
+                authn,
+                prompt,
+                timeouts,
+            );
+            match rt.block_on(launch) {
+                Ok(plugin) => {
+                    let _ = ready.send(Ok(plugin.public_key().to_vec()));
+                    worker(plugin, rt, rx);
+                }
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                }
+            }
+        })?;
+        match launched.await {
+            Ok(Ok(public_key)) => Ok(Self::from_parts(jobs, public_key)),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(LaunchError::Cancelled),
+        }
+    }
+
+    // Moves an already launched plugin onto its own thread. Unlike with `launch`, the plugin's pipes
+    // stay registered with the runtime it was opened on, which must keep running while the identity
+    // blocks on it. A current-thread runtime cannot, since blocking stops its only thread, so this
+    // refuses to be called from one.
+    pub fn new(plugin: SupervisedPlugin) -> Result<Self, LaunchError> {
+        if Handle::try_current()
+            .is_ok_and(|handle| handle.runtime_flavor() == RuntimeFlavor::CurrentThread)
+        {
+            return Err(LaunchError::CurrentThreadRuntime);
+        }
+        let rt = runtime()?;
+        let public_key = plugin.public_key().to_vec();
+        let (jobs, rx) = mpsc::channel();
+        spawn(move || worker(plugin, rt, rx))?;
+        Ok(Self::from_parts(jobs, public_key))
+    }
+
+    fn from_parts(jobs: Sender<Job>, public_key: Vec<u8>) -> Self {
         Self {
-            plugin: Mutex::new(plugin),
+            jobs,
+            principal: Principal::self_authenticating(&public_key),
+            public_key,
+            presigned: Mutex::new(HashMap::new()),
         }
     }
 
-    fn pubkey(&self) -> Result<Vec<u8>, String> {
-        block_on(self.plugin.lock().unwrap().public_key()).map_err(|e| format!("{e}"))
+    pub fn with_plugin<T: Send + 'static>(
+        &self,
+        f: impl AsyncFnOnce(&mut SupervisedPlugin) -> T + Send + 'static,
+    ) -> Result<T, ThreadExited> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let job: Job = Box::new(move |plugin, rt| {
+            let _ = tx.send(rt.block_on(f(plugin)));
+        });
+        self.jobs.send(job).map_err(|_| ThreadExited)?;
+        rx.recv().map_err(|_| ThreadExited)
+    }
+
+    fn call<T: Send + 'static>(
+        &self,
+        f: impl AsyncFnOnce(&mut SupervisedPlugin) -> Result<T, String> + Send + 'static,
+    ) -> Result<T, String> {
+        self.with_plugin(f).map_err(|e| format!("{e}"))?
+    }
+
+    pub fn delegated_identity(
+        &self,
+        desired_expiry: u64,
+        desired_canisters: Option<&[Principal]>,
+    ) -> Result<DelegatedIdentity, DelegateError> {
+        let desired_canisters = desired_canisters.map(<[Principal]>::to_vec);
+        self.with_plugin(async move |plugin| {
+            plugin
+                .delegated_identity(desired_expiry, desired_canisters.as_deref())
+                .await
+        })?
     }
 
-    pub fn with_plugin<T>(&self, f: impl FnOnce(&mut Plugin) -> T) -> T {
-        f(&mut self.plugin.lock().unwrap())
+    fn sign_envelopes(&self, contents: Vec<EnvelopeContent>) -> Result<Vec<Vec<u8>>, SignError> {
+        let len = contents.len();
+        let sigs =
+            self.with_plugin(async move |plugin| plugin.sign_envelopes(&contents).await)??;
+        if sigs.len() != len {
+            return Err(SignError::Plugin(SupervisorError::Plugin(
+                PluginError::Plugin(SignEnvelopesError::Custom {
+                    message: format!(
+                        "plugin returned {} signatures for {len} envelopes",
+                        sigs.len()
+                    ),
+                }),
+            )));
+        }
+        Ok(sigs)
+    }
+
+    fn sign_call(&self, content: &EnvelopeContent) -> Result<Vec<u8>, String> {
+        let read_state = EnvelopeContent::ReadState {
+            ingress_expiry: content.ingress_expiry(),
+            sender: *content.sender(),
+            paths: vec![vec![
+                Label::from("request_status"),
+                Label::from(content.to_request_id().to_vec()),
+            ]],
+        };
+        let read_state_id = read_state.to_request_id();
+        match self.sign_envelopes(vec![content.clone(), read_state]) {
+            Ok(mut sigs) => {
+                let signature = sigs.pop().unwrap();
+                let mut presigned = self.presigned.lock().unwrap();
+                let now = now();
+                presigned.retain(|_, presigned| presigned.ingress_expiry > now);
+                presigned.insert(
+                    read_state_id,
+                    Presigned {
+                        ingress_expiry: content.ingress_expiry(),
+                        signature,
+                    },
+                );
+                Ok(sigs.pop().unwrap())
+            }
+            // The plugin may be unwilling to sign the read_state even if it accepts the call.
+            Err(SignError::Plugin(SupervisorError::Plugin(PluginError::Plugin(
+                SignEnvelopesError::UnsupportedContent { pos, .. },
+            )))) if !pos.contains(&0) => self.sign_content(content),
+            Err(e) => Err(format!("{e}")),
+        }
     }
+
+    fn sign_content(&self, content: &EnvelopeContent) -> Result<Vec<u8>, String> {
+        if let EnvelopeContent::ReadState { .. } = content {
+            let presigned = self.presigned.lock().unwrap();
+            if let Some(presigned) = presigned.get(&content.to_request_id()) {
+                return Ok(presigned.signature.clone());
+            }
+        }
+        let mut sigs = self
+            .sign_envelopes(vec![content.clone()])
+            .map_err(|e| format!("{e}"))?;
+        Ok(sigs.remove(0))
+    }
+}
+
+impl Plugin<Authenticated> {
+    // The plugin cannot be restarted after authentication expiry; see `SupervisedPlugin::adopt`.
+    pub async fn into_identity(self) -> Result<PluginIdentity, LaunchError> {
+        let plugin = SupervisedPlugin::adopt(self).await?;
+        PluginIdentity::new(plugin)
+    }
+}
+
+fn runtime() -> Result<Runtime, LaunchError> {
+    Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| LaunchError::Open(e.into()))
+}
+
+fn spawn(f: impl FnOnce() + Send + 'static) -> Result<(), LaunchError> {
+    thread::Builder::new()
+        .name("ic-auth-plugin".into())
+        .spawn(f)
+        .map(drop)
+        .map_err(|e| LaunchError::Open(e.into()))
+}
+
+fn worker(mut plugin: SupervisedPlugin, rt: Runtime, jobs: Receiver<Job>) {
+    while let Ok(job) = jobs.recv() {
+        job(&mut plugin, &rt);
+    }
+    let _ = rt.block_on(plugin.shutdown(SHUTDOWN_GRACE));
 }
 
 impl Identity for PluginIdentity {
     fn sender(&self) -> Result<Principal, String> {
-        Ok(Principal::self_authenticating(&self.pubkey()?))
+        Ok(self.principal)
     }
     fn public_key(&self) -> Option<Vec<u8>> {
-        self.pubkey().ok()
+        Some(self.public_key.clone())
     }
     fn sign(&self, content: &EnvelopeContent) -> Result<Signature, String> {
-        let mut sigs = block_on(
-            self.plugin
-                .lock()
-                .unwrap()
-                .sign_envelopes(slice::from_ref(content)),
-        )
-        .map_err(|e| format!("{e}"))?;
+        let signature = match content {
+            EnvelopeContent::Call { .. } => self.sign_call(content)?,
+            _ => self.sign_content(content)?,
+        };
         Ok(Signature {
-            public_key: Some(self.pubkey()?),
-            signature: Some(sigs.remove(0)),
+            public_key: Some(self.public_key.clone()),
+            signature: Some(signature),
             delegations: None,
         })
     }
     fn sign_arbitrary(&self, content: &[u8]) -> Result<Signature, String> {
-        let sig = block_on(self.plugin.lock().unwrap().sign_arbitrary(content))
-            .map_err(|e| format!("{e}"))?;
+        let content = content.to_vec();
+        let sig = self.call(async move |plugin| {
+            plugin
+                .sign_arbitrary(&content)
+                .await
+                .map_err(|e| format!("{e}"))
+        })?;
         Ok(Signature {
-            public_key: Some(self.pubkey()?),
+            public_key: Some(self.public_key.clone()),
             signature: Some(sig),
             delegations: None,
         })
     }
     fn sign_delegation(&self, content: &Delegation) -> Result<Signature, String> {
-        let (sig, expiry) = block_on(self.plugin.lock().unwrap().sign_delegation(
-            &content.pubkey,
-            content.expiration as u128,
-            content.targets.as_deref(),
-        ))
-        .map_err(|e| format!("{e}"))?;
-        if expiry != content.expiration as u128 {
-            return Err("variable expiry not supported".to_string()); //todo
+        let expiration = content.expiration as u128;
+        let content = content.clone();
+        let (sig, expiry) = self.call(async move |plugin| {
+            plugin
+                .sign_delegation(
+                    &content.pubkey,
+                    content.expiration as u128,
+                    content.targets.as_deref(),
+                )
+                .await
+                .map_err(|e| format!("{e}"))
+        })?;
+        if expiry != expiration {
+            return Err(format!(
+                "plugin shortened the delegation expiry to {expiry}, use PluginIdentity::delegated_identity instead"
+            ));
         }
         Ok(Signature {
-            public_key: Some(self.pubkey()?),
+            public_key: Some(self.public_key.clone()),
             signature: Some(sig),
             delegations: None,
         })
     }
 }
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use ic_auth_plugin_types::Action;
+
+    use super::*;
+    use crate::{MockKey, MockPlugin};
+
+    async fn authenticated(mock: &MockPlugin) -> Plugin<Authenticated> {
+        mock.open()
+            .await
+            .unwrap()
+            .skip_key_selection()
+            .unwrap()
+            .authenticate(None, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_plugins_on_a_current_thread_runtime() {
+        let mock = MockPlugin::new(MockKey::new("a"));
+        assert!(matches!(
+            authenticated(&mock).await.into_identity().await,
+            Err(LaunchError::CurrentThreadRuntime)
+        ));
+    }
+
+    // The mock is served by the test's runtime, which has to keep running while the identity blocks.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_an_exited_plugin_thread_everywhere() {
+        let key = MockKey::new("a");
+        let mock = MockPlugin::new(key.clone());
+        let identity = authenticated(&mock).await.into_identity().await.unwrap();
+        assert_eq!(
+            identity.with_plugin(async |_| panic!("plugin thread panicked")),
+            Err(ThreadExited)
+        );
+        assert_eq!(identity.sender(), Ok(key.principal()));
+        let content = EnvelopeContent::Query {
+            ingress_expiry: now() + 60_000_000_000,
+            sender: key.principal(),
+            canister_id: Principal::from_slice(&[1]),
+            method_name: "greet".into(),
+            arg: vec![],
+            nonce: None,
+        };
+        let message = ThreadExited.to_string();
+        assert_eq!(identity.sign(&content).unwrap_err(), message);
+        assert_eq!(identity.sign_arbitrary(b"data").unwrap_err(), message);
+        assert!(matches!(
+            identity.delegated_identity(now() + 60_000_000_000, None),
+            Err(DelegateError::ThreadExited(ThreadExited))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn signs_the_status_poll_with_the_call() {
+        let key = MockKey::new("a");
+        let mock = MockPlugin::new(key.clone());
+        let identity = authenticated(&mock).await.into_identity().await.unwrap();
+        let call = EnvelopeContent::Call {
+            nonce: None,
+            ingress_expiry: now() + 60_000_000_000,
+            sender: key.principal(),
+            canister_id: Principal::from_slice(&[1]),
+            method_name: "greet".into(),
+            arg: vec![],
+        };
+        let read_state = EnvelopeContent::ReadState {
+            ingress_expiry: call.ingress_expiry(),
+            sender: key.principal(),
+            paths: vec![vec![
+                Label::from("request_status"),
+                Label::from(call.to_request_id().to_vec()),
+            ]],
+        };
+        let signature = identity.sign(&call).unwrap().signature.unwrap();
+        assert_eq!(signature, key.sign(&call.to_request_id().signable()));
+        let signature = identity.sign(&read_state).unwrap().signature.unwrap();
+        assert_eq!(signature, key.sign(&read_state.to_request_id().signable()));
+        assert_eq!(mock.count(Action::SignEnvelopes), 1);
+    }
+}
@@ -1,37 +1,91 @@
-use std::borrow::Cow;
-use std::convert::Infallible;
-use std::ffi::OsStr;
-use std::io::{self, Error as IoError, ErrorKind, IoSlice};
-use std::process::Stdio;
+use std::fmt::{self, Debug};
+use std::io::Error as IoError;
+use std::process::ExitStatus;
+use std::time::Duration;
 
-use ic_auth_plugin_types::{
-    AuthenticateError, AuthenticateRequest, AuthenticateResult, AuthnMode, DescribeAuthnModeError,
-    DescribeAuthnModeRequest, DescribeAuthnModeResult, GetPublicKeyError, GetPublicKeyRequest,
-    GetPublicKeyResult, Greeting, KeySelectError, KeySelectRequest, KeySelectResult,
-    ListSelectableKeysError, ListSelectableKeysRequest, ListSelectableKeysResponse,
-    ListSelectableKeysResult, Request, SelectMode, SignArbitraryDataError,
-    SignArbitraryDataRequest, SignArbitraryDataResult, SignDelegationError, SignDelegationRequest,
-    SignDelegationResult, SignEnvelopesError, SignEnvelopesRequest, SignEnvelopesResult,
-};
-use ic_principal::Principal;
-use ic_transport_types::EnvelopeContent;
+use ic_auth_plugin_types::{Action, ProtocolViolation};
 use thiserror::Error;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
-use tokio::process::{ChildStderr, ChildStdin, ChildStdout, Command};
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "identity")]
+mod delegation;
+#[cfg(any(feature = "async", feature = "blocking"))]
+mod encoding;
 #[cfg(feature = "identity")]
 mod identity;
+#[cfg(feature = "testing")]
+mod mock;
+#[cfg(feature = "async")]
+mod plugin;
+#[cfg(feature = "async")]
+mod pool;
+#[cfg(feature = "identity")]
+mod session;
+#[cfg(feature = "async")]
+mod supervisor;
+#[cfg(feature = "async")]
+mod transcript;
+#[cfg(feature = "async")]
+mod transport;
+#[cfg(feature = "identity")]
+pub use delegation::DelegateError;
 pub use ic_auth_plugin_types as types;
 #[cfg(feature = "identity")]
-pub use identity::PluginIdentity;
+pub use identity::{PluginIdentity, ThreadExited};
+#[cfg(all(test, unix, feature = "testing"))]
+pub(crate) use mock::ScriptPlugin;
+#[cfg(feature = "testing")]
+pub use mock::{MockAuthn, MockError, MockKey, MockPlugin, Received};
+#[cfg(feature = "async")]
+pub use plugin::Plugin;
+#[cfg(feature = "async")]
+pub use pool::{KeyRef, PluginPool, PoolError, PoolKey};
+#[cfg(feature = "identity")]
+pub use session::{CanisterScope, SessionConfig, SessionIdentity};
+#[cfg(feature = "async")]
+pub use supervisor::{AuthnInput, AuthnPrompt, LaunchError, SupervisedPlugin, SupervisorError};
+#[cfg(feature = "async")]
+pub use transcript::{Divergence, Entry, Replay, Side, Transcript, TranscriptError};
+#[cfg(feature = "async")]
+pub use transport::{Transport, TransportReader, TransportWriter};
+
+// Timeouts are per request. Since a late response would be read as the answer to the next request,
+// a plugin that times out (or whose request future is dropped before the response arrives) is
+// killed and every further request fails with `PluginError::Unusable`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Timeouts {
+    pub authenticate: Option<Duration>,
+    pub signing: Option<Duration>,
+    pub other: Option<Duration>,
+}
 
-pub struct Plugin {
-    stdin: BufWriter<ChildStdin>,
-    stdout: Lines<BufReader<ChildStdout>>,
-    stderr: Option<ChildStderr>,
-    select_mode: SelectMode,
+impl Timeouts {
+    pub fn for_action(&self, action: Action) -> Option<Duration> {
+        match action {
+            Action::Authenticate => self.authenticate,
+            Action::SignDelegation
+            | Action::SignEnvelopes
+            | Action::SignArbitraryData
+            | Action::Extension => self.signing,
+            Action::KeySelect
+            | Action::ListSelectableKeys
+            | Action::GetPublicKey
+            | Action::DescribeAuthnMode => self.other,
+        }
+    }
 }
 
+#[cfg(any(feature = "async", feature = "blocking"))]
+pub(crate) const EXIT_GRACE: Duration = Duration::from_secs(1);
+
+// Handshake states. Each state only exposes the requests SPEC.md allows in it; `Dynamic` exposes
+// all of them and relies on `ProtocolState` to reject out-of-order requests at runtime.
+pub struct Greeted;
+pub struct KeySelected;
+pub struct Authenticated;
+pub struct Dynamic;
+
 #[derive(Error, Debug)]
 pub enum PluginError<E> {
     #[error("plugin I/O error: {0}")]
@@ -40,206 +94,50 @@ pub enum PluginError<E> {
     Encoding(#[from] serde_json::Error),
     #[error("plugin was incompatible")]
     Incompatible,
+    #[error("plugin aborted: {message}")]
+    Aborted {
+        message: String,
+        status: Option<ExitStatus>,
+    },
+    #[error("protocol violation: {0}")]
+    Protocol(#[from] ProtocolViolation),
+    #[error("plugin timed out during {0}")]
+    Timeout(Action),
+    #[error("plugin is unusable after an interrupted request")]
+    Unusable,
+    #[error("plugin exited ({0})")]
+    Exited(ExitStatus),
     #[error("plugin error: {0}")]
     Plugin(E),
 }
 
-impl Plugin {
-    pub async fn open(program: impl AsRef<OsStr>) -> Result<Self, PluginError<Infallible>> {
-        Self::open_with_stderr(program, Stdio::inherit()).await
-    }
-
-    pub async fn open_with_stderr(
-        program: impl AsRef<OsStr>,
-        stderr: impl Into<Stdio>,
-    ) -> Result<Self, PluginError<Infallible>> {
-        let mut child = Command::new(program)
-            .arg("--ic-auth-plugin")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(stderr)
-            .spawn()?;
-        let stdin = BufWriter::new(child.stdin.take().unwrap());
-        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
-        let Some(greeting) = stdout.next_line().await? else {
-            return Err(PluginError::Io(IoError::from(ErrorKind::UnexpectedEof)));
-        };
-        let greeting: Greeting = serde_json::from_str(&greeting)?;
-        if !greeting.v.contains(&1) {
-            return Err(PluginError::Incompatible);
-        }
-        Ok(Self {
-            select_mode: greeting.select.unwrap_or(SelectMode::Unsupported),
-            stdin,
-            stdout,
-            stderr: child.stderr.take(),
-        })
-    }
-
-    pub fn select_mode(&self) -> SelectMode {
-        self.select_mode
-    }
-
-    pub async fn key_names(
-        &mut self,
-    ) -> Result<Option<ListSelectableKeysResponse>, PluginError<ListSelectableKeysError>> {
-        let req = serde_json::to_string(&Request::ListSelectableKeys(ListSelectableKeysRequest {
-            v: 1,
-        }))?;
-        self.writeln(&req).await?;
-        let resp = self.readln().await?;
-        let resp: ListSelectableKeysResult = serde_json::from_str(&resp)?;
-        match resp {
-            Ok(keys) => Ok(Some(keys)),
-            Err(ListSelectableKeysError::Unsupported) => Ok(None),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub async fn select_key(&mut self, key: &str) -> Result<(), PluginError<KeySelectError>> {
-        let req = serde_json::to_string(&Request::KeySelect(KeySelectRequest {
-            v: 1,
-            key: key.into(),
-        }))?;
-        self.writeln(&req).await?;
-
-        let resp = self.readln().await?;
-        let resp: KeySelectResult = serde_json::from_str(&resp)?;
-        match resp {
-            Ok(_) => Ok(()),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub async fn authn_mode(
-        &mut self,
-    ) -> Result<(AuthnMode, Option<String>), PluginError<DescribeAuthnModeError>> {
-        let req = serde_json::to_string(&Request::DescribeAuthnMode(DescribeAuthnModeRequest {
-            v: 1,
-        }))?;
-        self.writeln(&req).await?;
-        let resp = self.readln().await?;
-        let resp: DescribeAuthnModeResult = serde_json::from_str(&resp)?;
-        match resp {
-            Ok(mode) => Ok((mode.mode, mode.value)),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub async fn authenticate(
-        &mut self,
-        integrated_mode: Option<AuthnMode>,
-        integrated_value: Option<String>,
-    ) -> Result<(), PluginError<AuthenticateError>> {
-        let req = serde_json::to_string(&Request::Authenticate(AuthenticateRequest {
-            integrated: integrated_mode,
-            value: integrated_value.map(Cow::from),
-            v: 1,
-        }))?;
-        self.writeln(&req).await?;
-        let resp = self.readln().await?;
-        let resp: AuthenticateResult = serde_json::from_str(&resp)?;
-        match resp {
-            Ok(_) => Ok(()),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub async fn sign_envelopes(
-        &mut self,
-        envelopes: &[EnvelopeContent],
-    ) -> Result<Vec<Vec<u8>>, PluginError<SignEnvelopesError>> {
-        let req = serde_json::to_string(&Request::SignEnvelopes(SignEnvelopesRequest {
-            v: 1,
-            contents: envelopes.into(),
-        }))?;
-        self.writeln(&req).await?;
-        let resp = self.readln().await?;
-        let resp: SignEnvelopesResult = serde_json::from_str(&resp)?;
-        match resp {
-            #[allow(clippy::unnecessary_to_owned)] // false positive, the first into_owned is no-op
-            Ok(o) => Ok(o
-                .signatures
-                .into_owned()
-                .into_iter()
-                .map(|s| s.into_owned())
-                .collect()),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub async fn sign_delegation(
-        &mut self,
-        public_key_der: &[u8],
-        desired_expiry: u128,
-        desired_canisters: Option<&[Principal]>,
-    ) -> Result<(Vec<u8>, u128), PluginError<SignDelegationError>> {
-        let req = serde_json::to_string(&Request::SignDelegation(SignDelegationRequest {
-            v: 1,
-            public_key_der: public_key_der.into(),
-            desired_expiry,
-            desired_canisters: desired_canisters.map(Into::into),
-        }))?;
-        self.writeln(&req).await?;
-        let resp = self.readln().await?;
-        let resp: SignDelegationResult = serde_json::from_str(&resp)?;
-        match resp {
-            Ok(o) => Ok((o.signature.into_owned(), o.expiry)),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub async fn sign_arbitrary(
-        &mut self,
-        data: &[u8],
-    ) -> Result<Vec<u8>, PluginError<SignArbitraryDataError>> {
-        let req = serde_json::to_string(&Request::SignArbitraryData(SignArbitraryDataRequest {
-            v: 1,
-            data: data.into(),
-        }))?;
-        self.writeln(&req).await?;
-        let resp = self.readln().await?;
-        let resp: SignArbitraryDataResult = serde_json::from_str(&resp)?;
-        match resp {
-            Ok(o) => Ok(o.signature.into_owned()),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub async fn public_key(&mut self) -> Result<Vec<u8>, PluginError<GetPublicKeyError>> {
-        let req = serde_json::to_string(&Request::GetPublicKey(GetPublicKeyRequest { v: 1 }))?;
-        self.writeln(&req).await?;
-        let resp = self.readln().await?;
-        let resp: GetPublicKeyResult = serde_json::from_str(&resp)?;
-        match resp {
-            Ok(o) => Ok(o.public_key_der.into_owned()),
-            Err(e) => Err(PluginError::Plugin(e)),
-        }
-    }
-
-    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
-        self.stderr.take()
-    }
+// A failed handshake transition, handing the plugin (async or blocking) back in its previous state
+// so the step can be retried (e.g. after a wrong password).
+#[derive(Error)]
+#[error("{error}")]
+pub struct HandshakeError<P, E> {
+    pub plugin: P,
+    pub error: PluginError<E>,
+}
 
-    async fn writeln(&mut self, line: &str) -> io::Result<()> {
-        let bufs = [IoSlice::new(line.as_bytes()), IoSlice::new(b"\n")];
-        let mut len = line.len() + 1;
-        while len != 0 {
-            len -= self.stdin.write_vectored(&bufs).await?;
-        }
-        self.stdin.flush().await?;
-        Ok(())
+impl<P, E: Debug> Debug for HandshakeError<P, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandshakeError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
     }
+}
 
-    async fn readln(&mut self) -> io::Result<String> {
-        self.stdout
-            .next_line()
-            .await?
-            .ok_or_else(|| IoError::from(ErrorKind::UnexpectedEof))
+impl<P, E> From<HandshakeError<P, E>> for PluginError<E> {
+    fn from(err: HandshakeError<P, E>) -> Self {
+        err.error
     }
+}
 
-    #[cfg(feature = "identity")]
-    pub fn into_identity(self) -> PluginIdentity {
-        PluginIdentity::new(self)
+impl<E> PluginError<E> {
+    // SPEC.md: a plugin exiting with status zero signals that user authentication expired, and the
+    // host may restart it immediately.
+    pub fn is_authn_expiry(&self) -> bool {
+        matches!(self, Self::Exited(status) if status.success())
     }
 }
@@ -0,0 +1,654 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use ed25519_consensus::SigningKey;
+use ic_auth_plugin_server::{AuthPlugin, Server, ServerError};
+use ic_auth_plugin_types::{
+    Action, AuthenticateError, AuthenticateRequest, AuthenticateResponse, AuthenticateResult,
+    AuthnMode, DescribeAuthnModeError, DescribeAuthnModeRequest, DescribeAuthnModeResponse,
+    DescribeAuthnModeResult, ExtensionError, ExtensionRequest, ExtensionResult, GetPublicKeyError,
+    GetPublicKeyRequest, GetPublicKeyResponse, GetPublicKeyResult, KeySelectError,
+    KeySelectRequest, KeySelectResponse, KeySelectResult, ListSelectableKeysError,
+    ListSelectableKeysRequest, ListSelectableKeysResponse, ListSelectableKeysResult,
+    ProtocolViolation, Request, SelectMode, SignArbitraryDataError, SignArbitraryDataRequest,
+    SignArbitraryDataResponse, SignArbitraryDataResult, SignDelegationError, SignDelegationRequest,
+    SignDelegationResponse, SignDelegationResult, SignEnvelopesError, SignEnvelopesRequest,
+    SignEnvelopesResponse, SignEnvelopesResult,
+};
+use ic_principal::Principal;
+use ic_transport_types::Delegation;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+use crate::{Plugin, PluginError};
+
+// RFC 8410 SubjectPublicKeyInfo header for an Ed25519 key, followed by the 32 raw key bytes.
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+#[derive(Clone)]
+pub struct MockKey {
+    name: String,
+    signing_key: SigningKey,
+}
+
+impl MockKey {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            signing_key: SigningKey::new(rand::thread_rng()),
+        }
+    }
+
+    // Deterministic keys, so tests can hardcode the expected principals.
+    pub fn from_seed(name: impl Into<String>, seed: [u8; 32]) -> Self {
+        Self {
+            name: name.into(),
+            signing_key: SigningKey::from(seed),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn public_key_der(&self) -> Vec<u8> {
+        let mut der = ED25519_DER_PREFIX.to_vec();
+        der.extend_from_slice(self.signing_key.verification_key().as_bytes());
+        der
+    }
+
+    pub fn principal(&self) -> Principal {
+        Principal::self_authenticating(self.public_key_der())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(message).to_bytes().to_vec()
+    }
+}
+
+// One step of the authentication script. `describe-authn-mode` reports the current step, and each
+// `authenticate` attempt moves on to the next one; the last step repeats indefinitely.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MockAuthn {
+    pub mode: AuthnMode,
+    pub value: Option<String>,
+    // The value the host must pass back (e.g. the password) for the attempt to succeed.
+    pub expect: Option<String>,
+}
+
+impl Default for MockAuthn {
+    fn default() -> Self {
+        Self {
+            mode: AuthnMode::Automatic,
+            value: None,
+            expect: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MockError {
+    KeySelect(KeySelectError),
+    ListSelectableKeys(ListSelectableKeysError),
+    GetPublicKey(GetPublicKeyError),
+    DescribeAuthnMode(DescribeAuthnModeError),
+    Authenticate(AuthenticateError),
+    SignDelegation(SignDelegationError),
+    SignEnvelopes(SignEnvelopesError),
+    SignArbitraryData(SignArbitraryDataError),
+    Extension(ExtensionError),
+}
+
+impl MockError {
+    pub fn action(&self) -> Action {
+        match self {
+            Self::KeySelect(_) => Action::KeySelect,
+            Self::ListSelectableKeys(_) => Action::ListSelectableKeys,
+            Self::GetPublicKey(_) => Action::GetPublicKey,
+            Self::DescribeAuthnMode(_) => Action::DescribeAuthnMode,
+            Self::Authenticate(_) => Action::Authenticate,
+            Self::SignDelegation(_) => Action::SignDelegation,
+            Self::SignEnvelopes(_) => Action::SignEnvelopes,
+            Self::SignArbitraryData(_) => Action::SignArbitraryData,
+            Self::Extension(_) => Action::Extension,
+        }
+    }
+}
+
+macro_rules! mock_error_from {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(
+            impl From<$error> for MockError {
+                fn from(e: $error) -> Self {
+                    Self::$variant(e)
+                }
+            }
+        )*
+    };
+}
+
+mock_error_from!(
+    KeySelect(KeySelectError),
+    ListSelectableKeys(ListSelectableKeysError),
+    GetPublicKey(GetPublicKeyError),
+    DescribeAuthnMode(DescribeAuthnModeError),
+    Authenticate(AuthenticateError),
+    SignDelegation(SignDelegationError),
+    SignEnvelopes(SignEnvelopesError),
+    SignArbitraryData(SignArbitraryDataError),
+    Extension(ExtensionError),
+);
+
+#[derive(Debug, Clone)]
+pub struct Received {
+    action: Action,
+    line: String,
+}
+
+impl Received {
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn request(&self) -> Option<Request<'_>> {
+        match self.action {
+            Action::Extension => None,
+            _ => serde_json::from_str(&self.line).ok(),
+        }
+    }
+
+    pub fn extension_request(&self) -> Option<ExtensionRequest<'_>> {
+        match self.action {
+            Action::Extension => serde_json::from_str(&self.line).ok(),
+            _ => None,
+        }
+    }
+}
+
+struct MockState {
+    keys: Vec<MockKey>,
+    select_mode: SelectMode,
+    exhaustive: bool,
+    authn: VecDeque<MockAuthn>,
+    public_key_requires_authn: bool,
+    max_expiry: Option<u128>,
+    extensions: Vec<String>,
+    errors: HashMap<Action, VecDeque<MockError>>,
+    delays: HashMap<Action, VecDeque<Duration>>,
+    received: Vec<Received>,
+    violations: Vec<ProtocolViolation>,
+    connections: usize,
+}
+
+// The plugin side of the protocol, run in-process over an in-memory transport, for testing hosts.
+// It signs with software Ed25519 keys, follows a script of authentication modes, and can be told
+// to fail upcoming requests with any error. Every request it receives is recorded for assertions.
+// Clones share their configuration and records, and so do all connections made from them.
+#[derive(Clone)]
+pub struct MockPlugin {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockPlugin {
+    pub fn new(key: MockKey) -> Self {
+        Self::with_keys(SelectMode::Unsupported, vec![key])
+    }
+
+    // Until a key is selected, the first key is used.
+    pub fn with_keys(select_mode: SelectMode, keys: Vec<MockKey>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                keys,
+                select_mode,
+                exhaustive: true,
+                authn: VecDeque::from([MockAuthn::default()]),
+                public_key_requires_authn: false,
+                max_expiry: None,
+                extensions: Vec::new(),
+                errors: HashMap::new(),
+                delays: HashMap::new(),
+                received: Vec::new(),
+                violations: Vec::new(),
+                connections: 0,
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn set_exhaustive(&self, exhaustive: bool) {
+        self.state().exhaustive = exhaustive;
+    }
+
+    pub fn script_authn(&self, steps: impl IntoIterator<Item = MockAuthn>) {
+        let steps = VecDeque::from_iter(steps);
+        assert!(!steps.is_empty(), "authentication script must not be empty");
+        self.state().authn = steps;
+    }
+
+    pub fn set_public_key_requires_authn(&self, requires_authn: bool) {
+        self.state().public_key_requires_authn = requires_authn;
+    }
+
+    // Delegations are signed with the earlier of the host's desired expiry and this one.
+    pub fn set_max_expiry(&self, max_expiry: Option<u128>) {
+        self.state().max_expiry = max_expiry;
+    }
+
+    // Extension actions echo their payload back.
+    pub fn add_extension(&self, name: impl Into<String>) {
+        self.state().extensions.push(name.into());
+    }
+
+    // Injected errors are returned in order, one per request of the matching action, before the
+    // mock falls back to its normal behavior.
+    pub fn fail_next(&self, error: impl Into<MockError>) {
+        let error = error.into();
+        self.state()
+            .errors
+            .entry(error.action())
+            .or_default()
+            .push_back(error);
+    }
+
+    // Holds back the response to the next request of `action` for `delay`, e.g. to trip a timeout.
+    // Delays are used in order, like injected errors.
+    pub fn delay_next(&self, action: Action, delay: Duration) {
+        self.state()
+            .delays
+            .entry(action)
+            .or_default()
+            .push_back(delay);
+    }
+
+    // Serves a new connection on a background task of the current tokio runtime.
+    pub fn connect(&self) -> DuplexStream {
+        let (host, plugin) = io::duplex(64 * 1024);
+        self.state().connections += 1;
+        let conn = Connection {
+            mock: self.clone(),
+            selected: None,
+            authenticated: false,
+        };
+        tokio::spawn(conn.serve(plugin));
+        host
+    }
+
+    pub async fn open(&self) -> Result<Plugin, PluginError<Infallible>> {
+        Plugin::connect(self.connect()).await
+    }
+
+    pub fn connections(&self) -> usize {
+        self.state().connections
+    }
+
+    pub fn received(&self) -> Vec<Received> {
+        self.state().received.clone()
+    }
+
+    pub fn actions(&self) -> Vec<Action> {
+        self.state().received.iter().map(|r| r.action).collect()
+    }
+
+    pub fn count(&self, action: Action) -> usize {
+        self.state()
+            .received
+            .iter()
+            .filter(|r| r.action == action)
+            .count()
+    }
+
+    // Requests that broke the handshake order. The mock drops the connection after each one, as a
+    // real plugin would exit.
+    pub fn violations(&self) -> Vec<ProtocolViolation> {
+        self.state().violations.clone()
+    }
+
+    pub fn clear_received(&self) {
+        let mut state = self.state();
+        state.received.clear();
+        state.violations.clear();
+    }
+
+    #[track_caller]
+    pub fn assert_actions(&self, expected: &[Action]) {
+        assert_eq!(
+            self.actions(),
+            expected,
+            "mock plugin received other requests"
+        );
+    }
+
+    #[track_caller]
+    pub fn assert_received(&self, action: Action) {
+        assert!(self.count(action) > 0, "mock plugin received no {action}");
+    }
+
+    #[track_caller]
+    pub fn assert_not_received(&self, action: Action) {
+        assert_eq!(self.count(action), 0, "mock plugin received {action}");
+    }
+
+    #[track_caller]
+    pub fn assert_no_violations(&self) {
+        let violations = self.violations();
+        assert!(
+            violations.is_empty(),
+            "host violated the protocol: {violations:?}"
+        );
+    }
+}
+
+// A plugin that runs as a real child process, for tests a `MockPlugin` cannot cover, such as
+// plugins that exit. `script` is a shell script that answers with the canned response lines written
+// to its own directory; the directory is removed on drop.
+#[cfg(all(test, unix))]
+pub(crate) struct ScriptPlugin {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(all(test, unix))]
+impl ScriptPlugin {
+    pub(crate) fn new(name: &str, script: &str) -> Self {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir =
+            std::env::temp_dir().join(format!("ic-auth-plugin-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let plugin = Self { dir };
+        fs::write(plugin.path(), script).unwrap();
+        fs::set_permissions(plugin.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        plugin
+    }
+
+    // A plugin that greets with `greeting`, then answers each request with the line written under
+    // its action's name, or stalls if there is none. Once its stdin is closed it exits with status 3,
+    // unless a file named `linger` exists.
+    pub(crate) fn canned(name: &str, greeting: &ic_auth_plugin_types::Greeting) -> Self {
+        const SCRIPT: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+cat "$dir/greeting"
+while read -r line; do
+    action=$(echo "$line" | sed 's/.*"action":"\([^"]*\)".*/\1/')
+    if [ -e "$dir/$action" ]; then
+        cat "$dir/$action"
+    else
+        exec sleep 60
+    fi
+done
+if [ -e "$dir/linger" ]; then
+    exec sleep 60
+fi
+exit 3
+"#;
+        let plugin = Self::new(name, SCRIPT);
+        plugin.write("greeting", greeting);
+        plugin
+    }
+
+    pub(crate) fn path(&self) -> std::path::PathBuf {
+        self.dir.join("plugin.sh")
+    }
+
+    pub(crate) fn write(&self, name: &str, line: &impl serde::Serialize) {
+        let line = serde_json::to_string(line).unwrap() + "\n";
+        std::fs::write(self.dir.join(name), line).unwrap();
+    }
+
+    pub(crate) fn read(&self, name: &str) -> String {
+        std::fs::read_to_string(self.dir.join(name)).unwrap_or_default()
+    }
+}
+
+#[cfg(all(test, unix))]
+impl Drop for ScriptPlugin {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Connection {
+    mock: MockPlugin,
+    selected: Option<usize>,
+    authenticated: bool,
+}
+
+impl Connection {
+    async fn serve(self, stream: DuplexStream) {
+        let mock = self.mock.clone();
+        let (reader, mut writer) = io::split(stream);
+        let mut server = Server::new(self);
+        let mut lines = BufReader::new(reader).lines();
+        let greeting = serde_json::to_string(&server.greeting()).unwrap();
+        if writeln(&mut writer, &greeting).await.is_err() {
+            return;
+        }
+        while let Ok(Some(line)) = lines.next_line().await {
+            let mut delay = None;
+            if let Some(action) = action(&line) {
+                let mut state = mock.state();
+                state.received.push(Received {
+                    action,
+                    line: line.clone(),
+                });
+                delay = state.delays.get_mut(&action).and_then(VecDeque::pop_front);
+            }
+            match server.handle(&line) {
+                Ok(response) => {
+                    if let Some(delay) = delay {
+                        tokio::time::sleep(delay).await;
+                    }
+                    if writeln(&mut writer, &response).await.is_err() {
+                        return;
+                    }
+                }
+                Err(ServerError::Protocol(violation)) => {
+                    mock.state().violations.push(violation);
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.mock.state()
+    }
+
+    fn key(&self) -> Option<MockKey> {
+        self.state().keys.get(self.selected.unwrap_or(0)).cloned()
+    }
+
+    fn injected(&mut self, action: Action) -> Option<MockError> {
+        self.state().errors.get_mut(&action)?.pop_front()
+    }
+}
+
+async fn writeln(writer: &mut (impl AsyncWriteExt + Unpin), line: &str) -> io::Result<()> {
+    writer.write_all(format!("{line}\n").as_bytes()).await?;
+    writer.flush().await
+}
+
+fn action(line: &str) -> Option<Action> {
+    let ext = serde_json::from_str::<ExtensionRequest>(line).ok()?;
+    if ext.v.extension_name().is_some() {
+        return Some(Action::Extension);
+    }
+    serde_json::from_str::<Request>(line)
+        .ok()
+        .map(|req| req.action())
+}
+
+fn no_key() -> String {
+    "mock plugin has no keys".into()
+}
+
+impl AuthPlugin for Connection {
+    fn select_mode(&self) -> SelectMode {
+        self.state().select_mode
+    }
+
+    fn list_selectable_keys(
+        &mut self,
+        _req: ListSelectableKeysRequest,
+    ) -> ListSelectableKeysResult {
+        if let Some(MockError::ListSelectableKeys(e)) = self.injected(Action::ListSelectableKeys) {
+            return Err(e);
+        }
+        let state = self.state();
+        if state.select_mode == SelectMode::Unsupported {
+            return Err(ListSelectableKeysError::Unsupported);
+        }
+        Ok(ListSelectableKeysResponse {
+            keys: state.keys.iter().map(|k| k.name.clone()).collect(),
+            exhaustive: state.exhaustive,
+        })
+    }
+
+    fn select_key(&mut self, req: KeySelectRequest<'_>) -> KeySelectResult {
+        if let Some(MockError::KeySelect(e)) = self.injected(Action::KeySelect) {
+            return Err(e);
+        }
+        let state = self.state();
+        if state.select_mode == SelectMode::Unsupported {
+            return Err(KeySelectError::Unsupported);
+        }
+        let Some(pos) = state.keys.iter().position(|k| k.name == req.key) else {
+            return Err(KeySelectError::InvalidKey {
+                message: Some(format!("no key named {}", req.key)),
+            });
+        };
+        drop(state);
+        self.selected = Some(pos);
+        Ok(KeySelectResponse {})
+    }
+
+    fn describe_authn_mode(&mut self, _req: DescribeAuthnModeRequest) -> DescribeAuthnModeResult {
+        if let Some(MockError::DescribeAuthnMode(e)) = self.injected(Action::DescribeAuthnMode) {
+            return Err(e);
+        }
+        let step = self.state().authn[0].clone();
+        Ok(DescribeAuthnModeResponse {
+            mode: step.mode,
+            value: step.value,
+        })
+    }
+
+    fn authenticate(&mut self, req: AuthenticateRequest<'_>) -> AuthenticateResult {
+        if let Some(MockError::Authenticate(e)) = self.injected(Action::Authenticate) {
+            return Err(e);
+        }
+        let step = {
+            let mut state = self.state();
+            if state.authn.len() > 1 {
+                state.authn.pop_front().unwrap()
+            } else {
+                state.authn[0].clone()
+            }
+        };
+        if req.integrated.is_some_and(|mode| mode != step.mode) {
+            return Err(AuthenticateError::BadMode);
+        }
+        if step.expect.is_some() && step.expect.as_deref() != req.value.as_deref() {
+            return Err(AuthenticateError::BadAuthn {
+                message: "incorrect value".into(),
+            });
+        }
+        self.authenticated = true;
+        Ok(AuthenticateResponse {})
+    }
+
+    fn get_public_key(&mut self, _req: GetPublicKeyRequest) -> GetPublicKeyResult<'_> {
+        if let Some(MockError::GetPublicKey(e)) = self.injected(Action::GetPublicKey) {
+            return Err(e);
+        }
+        if self.state().public_key_requires_authn && !self.authenticated {
+            return Err(GetPublicKeyError::RequiresAuthn);
+        }
+        let key = self
+            .key()
+            .ok_or_else(|| GetPublicKeyError::Custom { message: no_key() })?;
+        Ok(GetPublicKeyResponse {
+            public_key_der: key.public_key_der().into(),
+        })
+    }
+
+    fn sign_envelopes(&mut self, req: SignEnvelopesRequest<'_>) -> SignEnvelopesResult<'_> {
+        if let Some(MockError::SignEnvelopes(e)) = self.injected(Action::SignEnvelopes) {
+            return Err(e);
+        }
+        let key = self
+            .key()
+            .ok_or_else(|| SignEnvelopesError::Custom { message: no_key() })?;
+        let signatures = req
+            .contents
+            .iter()
+            .map(|content| Cow::Owned(key.sign(&content.to_request_id().signable())))
+            .collect::<Vec<_>>();
+        Ok(SignEnvelopesResponse {
+            signatures: signatures.into(),
+        })
+    }
+
+    fn sign_delegation(&mut self, req: SignDelegationRequest<'_>) -> SignDelegationResult<'_> {
+        if let Some(MockError::SignDelegation(e)) = self.injected(Action::SignDelegation) {
+            return Err(e);
+        }
+        let key = self
+            .key()
+            .ok_or_else(|| SignDelegationError::Custom { message: no_key() })?;
+        let max_expiry = self.state().max_expiry.unwrap_or(u128::MAX);
+        let expiration = u64::try_from(req.desired_expiry.min(max_expiry)).unwrap_or(u64::MAX);
+        let delegation = Delegation {
+            pubkey: req.public_key_der.into_owned(),
+            expiration,
+            targets: req.desired_canisters.map(Cow::into_owned),
+        };
+        Ok(SignDelegationResponse {
+            signature: key.sign(&delegation.signable()).into(),
+            expiry: expiration.into(),
+        })
+    }
+
+    fn sign_arbitrary_data(
+        &mut self,
+        req: SignArbitraryDataRequest<'_>,
+    ) -> SignArbitraryDataResult<'_> {
+        if let Some(MockError::SignArbitraryData(e)) = self.injected(Action::SignArbitraryData) {
+            return Err(e);
+        }
+        let key = self
+            .key()
+            .ok_or_else(|| SignArbitraryDataError::Custom { message: no_key() })?;
+        Ok(SignArbitraryDataResponse {
+            signature: key.sign(&req.data).into(),
+        })
+    }
+
+    fn extensions(&self) -> Vec<String> {
+        self.state().extensions.clone()
+    }
+
+    fn extension(&mut self, req: ExtensionRequest<'_>) -> ExtensionResult {
+        if let Some(MockError::Extension(e)) = self.injected(Action::Extension) {
+            return Err(e);
+        }
+        let name = req.v.extension_name().unwrap_or_default();
+        if !self.state().extensions.iter().any(|ext| ext == name) {
+            return Err(ExtensionError::unsupported());
+        }
+        Ok(req.payload)
+    }
+}
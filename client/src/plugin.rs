@@ -0,0 +1,499 @@
+use std::convert::Infallible;
+use std::ffi::OsStr;
+use std::io::{self, Error as IoError, ErrorKind};
+use std::marker::PhantomData;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
+use tokio::process::{Child, ChildStderr, Command};
+
+use crate::encoding::{Operation, Protocol, typestate_api};
+use crate::transcript::{self, Side};
+use crate::{
+    EXIT_GRACE, Greeted, PluginError, Timeouts, Transcript, Transport, TransportReader,
+    TransportWriter,
+};
+
+pub struct Plugin<S = Greeted> {
+    io: Box<PluginIo>,
+    protocol: Protocol,
+    _state: PhantomData<S>,
+}
+
+// `child` is only present when the plugin runs as a child process; exit statuses and the process
+// lifecycle are unavailable over other transports.
+struct PluginIo {
+    child: Option<Child>,
+    writer: Option<BufWriter<TransportWriter>>,
+    reader: Lines<BufReader<TransportReader>>,
+    stderr: Option<ChildStderr>,
+    timeouts: Timeouts,
+    in_flight: bool,
+    unusable: bool,
+    // Kept so that a transcript attached after the handshake still starts with the greeting.
+    greeting: (u64, String),
+    transcript: Option<Transcript>,
+}
+
+impl Plugin {
+    pub async fn open(program: impl AsRef<OsStr>) -> Result<Self, PluginError<Infallible>> {
+        Self::open_with_stderr(program, Stdio::inherit()).await
+    }
+
+    pub async fn open_with_stderr(
+        program: impl AsRef<OsStr>,
+        stderr: impl Into<Stdio>,
+    ) -> Result<Self, PluginError<Infallible>> {
+        let child = Command::new(program)
+            .arg("--ic-auth-plugin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(stderr)
+            .kill_on_drop(true)
+            .spawn()?;
+        Self::from_child(child).await
+    }
+
+    // Takes over an already spawned plugin process, whose stdin and stdout must be piped. The process
+    // is only killed on drop if it was spawned with `kill_on_drop`.
+    pub async fn from_child(mut child: Child) -> Result<Self, PluginError<Infallible>> {
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(PluginError::Io(IoError::new(
+                ErrorKind::InvalidInput,
+                "plugin stdin and stdout must be piped",
+            )));
+        };
+        let stderr = child.stderr.take();
+        Self::greet(PluginIo::new(
+            (stdout, stdin).into_split(),
+            Some(child),
+            stderr,
+        ))
+        .await
+    }
+
+    pub async fn connect(transport: impl Transport) -> Result<Self, PluginError<Infallible>> {
+        Self::greet(PluginIo::new(transport.into_split(), None, None)).await
+    }
+
+    async fn greet(mut io: Box<PluginIo>) -> Result<Self, PluginError<Infallible>> {
+        let greeting = match io.readln().await {
+            Ok(greeting) => greeting,
+            Err(e) => return Err(io.exit_error(e).await),
+        };
+        io.greeting = (transcript::now_millis(), greeting.clone());
+        match Protocol::greet(&greeting) {
+            Ok(protocol) => Ok(Self {
+                io,
+                protocol,
+                _state: PhantomData,
+            }),
+            Err(PluginError::Aborted { message, .. }) => {
+                io.close().await;
+                // A plugin that aborts is expected to exit, but one that lingers must not block
+                // `open`.
+                let status = match &mut io.child {
+                    Some(child) => match tokio::time::timeout(EXIT_GRACE, child.wait()).await {
+                        Ok(status) => status.ok(),
+                        Err(_) => {
+                            let _ = child.kill().await;
+                            child.wait().await.ok()
+                        }
+                    },
+                    None => None,
+                };
+                Err(PluginError::Aborted { message, status })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+typestate_api!(async; .await);
+
+impl<S> Plugin<S> {
+    // Tees every line exchanged from now on, preceded by the greeting, into `transcript`.
+    pub fn record(&mut self, transcript: Transcript) {
+        let (at, greeting) = &self.io.greeting;
+        transcript.record_at(*at, Side::Plugin, greeting);
+        self.io.transcript = Some(transcript);
+    }
+
+    pub fn take_stderr(&mut self) -> Option<ChildStderr> {
+        self.io.stderr.take()
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.io.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.io.timeouts = timeouts;
+    }
+
+    pub fn is_usable(&self) -> bool {
+        !self.io.unusable && !self.io.in_flight
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        self.io.child.as_ref()?.id()
+    }
+
+    pub fn exit_status(&mut self) -> io::Result<Option<ExitStatus>> {
+        match &mut self.io.child {
+            Some(child) => child.try_wait(),
+            None => Ok(None),
+        }
+    }
+
+    // Closes the plugin's stdin, its signal to shut down gracefully, and kills it if it has not
+    // exited within `grace`. Dropping a `Plugin` without calling this kills the process outright.
+    // Over other transports, this waits up to `grace` for the plugin to close the connection, and
+    // returns no exit status.
+    pub async fn shutdown(self, grace: Duration) -> io::Result<Option<ExitStatus>> {
+        let mut io = self.io;
+        io.close().await;
+        match &mut io.child {
+            Some(child) => match tokio::time::timeout(grace, child.wait()).await {
+                Ok(status) => status.map(Some),
+                Err(_) => {
+                    child.kill().await?;
+                    child.wait().await.map(Some)
+                }
+            },
+            None => {
+                let drain = async {
+                    while io.reader.next_line().await?.is_some() {}
+                    io::Result::Ok(())
+                };
+                let _ = tokio::time::timeout(grace, drain).await;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn call<O: Operation>(&mut self, op: O) -> Result<O::Output, PluginError<O::Error>> {
+        let action = op.action();
+        if !self.is_usable() {
+            self.io.poison();
+            return Err(PluginError::Unusable);
+        }
+        let req = self.protocol.request(&op)?;
+        self.io.in_flight = true;
+        let resp = match self.io.timeouts.for_action(action) {
+            Some(timeout) => match tokio::time::timeout(timeout, self.io.exchange(&req)).await {
+                Ok(resp) => resp,
+                Err(_) => {
+                    self.io.poison();
+                    return Err(PluginError::Timeout(action));
+                }
+            },
+            None => self.io.exchange(&req).await,
+        };
+        // Only a dropped future leaves the request in flight; an I/O error is reported as itself.
+        self.io.in_flight = false;
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => return Err(self.io.exit_error(e).await),
+        };
+        self.protocol.response::<O>(action, &resp)
+    }
+}
+
+impl PluginIo {
+    fn new(
+        (reader, writer): (TransportReader, TransportWriter),
+        child: Option<Child>,
+        stderr: Option<ChildStderr>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            child,
+            writer: Some(BufWriter::new(writer)),
+            reader: BufReader::new(reader).lines(),
+            stderr,
+            timeouts: Timeouts::default(),
+            in_flight: false,
+            unusable: false,
+            greeting: (0, String::new()),
+            transcript: None,
+        })
+    }
+
+    async fn exchange(&mut self, line: &str) -> io::Result<String> {
+        self.writeln(line).await?;
+        self.readln().await
+    }
+
+    async fn writeln(&mut self, line: &str) -> io::Result<()> {
+        if let Some(transcript) = &self.transcript {
+            transcript.record(Side::Host, line);
+        }
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| IoError::from(ErrorKind::BrokenPipe))?;
+        writer.write_all(line.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+        writer.flush().await
+    }
+
+    async fn readln(&mut self) -> io::Result<String> {
+        let line = self
+            .reader
+            .next_line()
+            .await?
+            .ok_or_else(|| IoError::from(ErrorKind::UnexpectedEof))?;
+        if let Some(transcript) = &self.transcript {
+            transcript.record(Side::Plugin, &line);
+        }
+        Ok(line)
+    }
+
+    // A closed pipe usually means the plugin exited; report its status if it is available promptly.
+    async fn exit_error<E>(&mut self, e: IoError) -> PluginError<E> {
+        if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe) {
+            if let Some(child) = &mut self.child {
+                if let Ok(Ok(status)) = tokio::time::timeout(EXIT_GRACE, child.wait()).await {
+                    return PluginError::Exited(status);
+                }
+            }
+        }
+        PluginError::Io(e)
+    }
+
+    // Shuts down the write half, which the plugin sees as EOF.
+    async fn close(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = writer.shutdown().await;
+        }
+    }
+
+    fn poison(&mut self) {
+        self.unusable = true;
+        self.writer = None;
+        if let Some(child) = &mut self.child {
+            let _ = child.start_kill();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::time::Duration;
+
+    use ic_auth_plugin_types::{
+        Action, AuthenticateError, AuthnMode, Greeting, ProtocolVersion, ProtocolViolation,
+        SelectMode,
+    };
+
+    use crate::{
+        Entry, MockAuthn, MockKey, MockPlugin, Plugin, PluginError, Replay, Side, Timeouts,
+    };
+
+    fn keys() -> Vec<MockKey> {
+        vec![
+            MockKey::from_seed("a", [1; 32]),
+            MockKey::from_seed("b", [2; 32]),
+        ]
+    }
+
+    fn greeting(line: &str) -> Replay {
+        Replay::from_entries(vec![Entry {
+            at: 0,
+            from: Side::Plugin,
+            line: line.into(),
+        }])
+    }
+
+    #[tokio::test]
+    async fn runs_the_handshake_in_order() {
+        let mock = MockPlugin::with_keys(SelectMode::Supported, keys());
+        mock.script_authn([MockAuthn {
+            mode: AuthnMode::Password,
+            value: None,
+            expect: Some("hunter2".into()),
+        }]);
+        let mut plugin = mock.open().await.unwrap();
+        assert_eq!(plugin.key_names().await.unwrap().unwrap().keys.len(), 2);
+        let mut plugin = plugin.select_key("b").await.unwrap();
+        assert_eq!(
+            plugin.authn_mode().await.unwrap(),
+            (AuthnMode::Password, None)
+        );
+        let Err(err) = plugin
+            .authenticate(Some(AuthnMode::Password), Some("wrong".into()))
+            .await
+        else {
+            panic!("wrong password was accepted");
+        };
+        assert!(matches!(
+            err.error,
+            PluginError::Plugin(AuthenticateError::BadAuthn { .. })
+        ));
+        let mut plugin = err
+            .plugin
+            .authenticate(Some(AuthnMode::Password), Some("hunter2".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            plugin.public_key().await.unwrap(),
+            keys()[1].public_key_der()
+        );
+        assert_eq!(
+            plugin.sign_arbitrary(b"data").await.unwrap(),
+            keys()[1].sign(b"data")
+        );
+        mock.assert_actions(&[
+            Action::ListSelectableKeys,
+            Action::KeySelect,
+            Action::DescribeAuthnMode,
+            Action::Authenticate,
+            Action::Authenticate,
+            Action::GetPublicKey,
+            Action::SignArbitraryData,
+        ]);
+        mock.assert_no_violations();
+    }
+
+    #[tokio::test]
+    async fn refuses_to_skip_required_selection() {
+        let mock = MockPlugin::with_keys(SelectMode::Required, keys());
+        let Err(err) = mock.open().await.unwrap().skip_key_selection() else {
+            panic!("skipped required key selection");
+        };
+        assert!(matches!(
+            err.error,
+            PluginError::Protocol(ProtocolViolation::SelectRequired(Action::Authenticate))
+        ));
+        err.plugin.select_key("a").await.unwrap();
+        mock.assert_actions(&[Action::KeySelect]);
+    }
+
+    #[tokio::test]
+    async fn dynamic_plugins_check_order_before_sending() {
+        let mock = MockPlugin::new(MockKey::new("a"));
+        let mut plugin = mock.open().await.unwrap().into_dynamic();
+        assert!(matches!(
+            plugin.sign_arbitrary(b"data").await,
+            Err(PluginError::Protocol(ProtocolViolation::NotAuthenticated(
+                Action::SignArbitraryData
+            )))
+        ));
+        plugin.authenticate(None, None).await.unwrap();
+        plugin.sign_arbitrary(b"data").await.unwrap();
+        assert!(plugin.into_authenticated().is_ok());
+        mock.assert_actions(&[Action::Authenticate, Action::SignArbitraryData]);
+    }
+
+    #[tokio::test]
+    async fn reports_aborts_and_incompatible_greetings() {
+        match Plugin::connect(greeting(r#"{"v":[1],"abort":"no device"}"#)).await {
+            Err(PluginError::Aborted { message, status }) => {
+                assert_eq!(message, "no device");
+                assert_eq!(status, None);
+            }
+            _ => panic!("abort was not reported"),
+        }
+        assert!(matches!(
+            Plugin::connect(greeting(r#"{"v":[2]}"#)).await,
+            Err(PluginError::Incompatible)
+        ));
+    }
+
+    #[tokio::test]
+    async fn timed_out_and_dropped_requests_poison_the_plugin() {
+        let mock = MockPlugin::new(MockKey::new("a"));
+        mock.delay_next(Action::GetPublicKey, Duration::from_secs(60));
+        let mut plugin = mock.open().await.unwrap().into_dynamic();
+        plugin.authenticate(None, None).await.unwrap();
+        plugin.set_timeouts(Timeouts {
+            other: Some(Duration::from_millis(50)),
+            ..Timeouts::default()
+        });
+        assert!(matches!(
+            plugin.public_key().await,
+            Err(PluginError::Timeout(Action::GetPublicKey))
+        ));
+        assert!(!plugin.is_usable());
+        assert!(matches!(
+            plugin.sign_arbitrary(b"data").await,
+            Err(PluginError::Unusable)
+        ));
+
+        mock.delay_next(Action::GetPublicKey, Duration::from_secs(60));
+        let mut plugin = mock.open().await.unwrap().into_dynamic();
+        plugin.authenticate(None, None).await.unwrap();
+        let dropped = tokio::time::timeout(Duration::from_millis(50), plugin.public_key()).await;
+        assert!(dropped.is_err());
+        assert!(matches!(
+            plugin.public_key().await,
+            Err(PluginError::Unusable)
+        ));
+    }
+
+    #[cfg(unix)]
+    fn exiting_plugin(name: &str) -> crate::ScriptPlugin {
+        crate::ScriptPlugin::canned(
+            name,
+            &Greeting {
+                v: vec![ProtocolVersion::V1],
+                select: None,
+                abort: None,
+            },
+        )
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_reports_the_exit_status() {
+        let script = exiting_plugin("shutdown");
+        let mut plugin = Plugin::open(script.path()).await.unwrap();
+        assert!(plugin.exit_status().unwrap().is_none());
+        let status = plugin.shutdown(Duration::from_secs(10)).await.unwrap();
+        assert_eq!(status.and_then(|status| status.code()), Some(3));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_kills_plugins_that_ignore_eof() {
+        let script = exiting_plugin("shutdown-linger");
+        script.write("linger", &());
+        let plugin = Plugin::open(script.path()).await.unwrap();
+        let status = plugin
+            .shutdown(Duration::from_millis(100))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!status.success());
+        assert_eq!(status.code(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_extensions() {
+        let mock = MockPlugin::new(MockKey::new("a"));
+        mock.add_extension("echo");
+        let plugin = mock.open().await.unwrap();
+        assert!(plugin.supports_extension("echo"));
+        let mut plugin = plugin
+            .skip_key_selection()
+            .unwrap()
+            .authenticate(None, None)
+            .await
+            .unwrap();
+        let mut payload = serde_json::Map::new();
+        payload.insert("n".into(), 1.into());
+        assert_eq!(
+            plugin
+                .extension_request("echo", "ping", payload.clone())
+                .await
+                .unwrap(),
+            payload
+        );
+        assert!(matches!(
+            plugin.extension_request("other", "ping", payload).await,
+            Err(PluginError::Incompatible)
+        ));
+        mock.assert_actions(&[Action::Authenticate, Action::Extension]);
+    }
+}
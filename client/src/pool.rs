@@ -0,0 +1,409 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Display};
+use std::io;
+use std::mem;
+use std::time::Duration;
+
+use futures::future::{join_all, try_join_all};
+use ic_auth_plugin_types::{AuthnMode, ListSelectableKeysError, SelectMode};
+use ic_principal::Principal;
+use thiserror::Error;
+
+use crate::supervisor::select;
+use crate::{
+    AuthnInput, KeySelected, LaunchError, Plugin, PluginError, SupervisedPlugin, Timeouts,
+};
+
+#[derive(Error, Debug)]
+pub enum PoolError {
+    #[error(transparent)]
+    Launch(#[from] LaunchError),
+    #[error("failed to list keys: {0}")]
+    ListKeys(#[from] PluginError<ListSelectableKeysError>),
+    #[error("plugin requires a key to be selected, but did not list any")]
+    NoKeys,
+    #[error("no {0} in the pool")]
+    UnknownKey(String),
+    #[error("{0} is already in the pool")]
+    DuplicateKey(String),
+    #[error("{0} is not authenticated")]
+    NotAuthenticated(String),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum KeyRef<'a> {
+    Name(&'a str),
+    Principal(Principal),
+    // The plugin's own key, when it does not support key selection.
+    Default,
+}
+
+impl Display for KeyRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "key {name}"),
+            Self::Principal(principal) => write!(f, "key with principal {principal}"),
+            Self::Default => f.write_str("default key"),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for KeyRef<'a> {
+    fn from(name: &'a str) -> Self {
+        Self::Name(name)
+    }
+}
+
+impl From<Principal> for KeyRef<'_> {
+    fn from(principal: Principal) -> Self {
+        Self::Principal(principal)
+    }
+}
+
+pub struct PoolKey {
+    name: Option<String>,
+    public_key: Option<Vec<u8>>,
+    principal: Option<Principal>,
+    instance: Instance,
+}
+
+enum Instance {
+    Selected(Plugin<KeySelected>),
+    Authenticated(SupervisedPlugin),
+    // Left behind when authentication fails; the key is reopened on the next attempt.
+    Closed,
+}
+
+impl PoolKey {
+    async fn open(
+        program: &OsStr,
+        name: Option<String>,
+        plugin: Option<Plugin>,
+        timeouts: Timeouts,
+    ) -> Result<Self, LaunchError> {
+        let mut plugin = match plugin {
+            Some(plugin) => plugin,
+            None => Plugin::open(program).await?,
+        };
+        plugin.set_timeouts(timeouts);
+        let mut plugin = match &name {
+            Some(name) => plugin.select_key(name).await?,
+            None => plugin.skip_key_selection()?,
+        };
+        // Plugins may require authentication before revealing the key.
+        let public_key = match plugin.public_key().await {
+            Ok(public_key) => Some(public_key),
+            Err(PluginError::Plugin(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let mut key = Self {
+            name,
+            public_key: None,
+            principal: None,
+            instance: Instance::Selected(plugin),
+        };
+        if let Some(public_key) = public_key {
+            key.set_public_key(public_key);
+        }
+        Ok(key)
+    }
+
+    fn set_public_key(&mut self, public_key: Vec<u8>) {
+        self.principal = Some(Principal::self_authenticating(&public_key));
+        self.public_key = Some(public_key);
+    }
+
+    fn matches(&self, key: KeyRef<'_>) -> bool {
+        match key {
+            KeyRef::Name(name) => self.name.as_deref() == Some(name),
+            KeyRef::Principal(principal) => self.principal == Some(principal),
+            KeyRef::Default => self.name.is_none(),
+        }
+    }
+
+    fn key_ref(&self) -> KeyRef<'_> {
+        match &self.name {
+            Some(name) => KeyRef::Name(name),
+            None => KeyRef::Default,
+        }
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_deref()
+    }
+
+    pub fn principal(&self) -> Option<Principal> {
+        self.principal
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        matches!(self.instance, Instance::Authenticated(_))
+    }
+
+    pub fn plugin_mut(&mut self) -> Option<&mut SupervisedPlugin> {
+        match &mut self.instance {
+            Instance::Authenticated(plugin) => Some(plugin),
+            _ => None,
+        }
+    }
+}
+
+// SPEC.md: each plugin process represents a single key, so using several keys means running one
+// instance per key. The pool lists the plugin's keys, opens an instance for each with the key
+// selected, and authenticates them individually on demand. Public keys (and therefore principals)
+// may only be known once a key is authenticated.
+pub struct PluginPool {
+    program: OsString,
+    timeouts: Timeouts,
+    select_mode: SelectMode,
+    exhaustive: bool,
+    keys: Vec<PoolKey>,
+}
+
+impl PluginPool {
+    pub async fn launch(program: impl AsRef<OsStr>) -> Result<Self, PoolError> {
+        Self::launch_with_timeouts(program, Timeouts::default()).await
+    }
+
+    pub async fn launch_with_timeouts(
+        program: impl AsRef<OsStr>,
+        timeouts: Timeouts,
+    ) -> Result<Self, PoolError> {
+        let program = program.as_ref().to_owned();
+        let mut first = Plugin::open(&program).await.map_err(LaunchError::from)?;
+        first.set_timeouts(timeouts);
+        let select_mode = first.select_mode();
+        let listed = match select_mode {
+            SelectMode::Unsupported => None,
+            _ => first.key_names().await?,
+        };
+        let exhaustive = listed.as_ref().is_none_or(|listed| listed.exhaustive);
+        let names = match listed {
+            Some(listed) if !listed.keys.is_empty() => listed.keys.into_iter().map(Some).collect(),
+            _ if select_mode == SelectMode::Required => return Err(PoolError::NoKeys),
+            _ => vec![None],
+        };
+        let mut first = Some(first);
+        let keys = try_join_all(
+            names
+                .into_iter()
+                .map(|name| PoolKey::open(&program, name, first.take(), timeouts)),
+        )
+        .await?;
+        Ok(Self {
+            program,
+            timeouts,
+            select_mode,
+            exhaustive,
+            keys,
+        })
+    }
+
+    pub fn program(&self) -> &OsStr {
+        &self.program
+    }
+
+    pub fn select_mode(&self) -> SelectMode {
+        self.select_mode
+    }
+
+    // Whether the plugin claimed to have listed all of its keys. If not, others can be added by name.
+    pub fn is_exhaustive(&self) -> bool {
+        self.exhaustive
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &PoolKey> {
+        self.keys.iter()
+    }
+
+    // Each key has its own instance, so the plugins of different keys can be driven concurrently.
+    pub fn keys_mut(&mut self) -> impl Iterator<Item = &mut PoolKey> {
+        self.keys.iter_mut()
+    }
+
+    pub fn key<'a>(&self, key: impl Into<KeyRef<'a>>) -> Option<&PoolKey> {
+        let key = key.into();
+        self.keys.iter().find(|k| k.matches(key))
+    }
+
+    pub fn key_mut<'a>(&mut self, key: impl Into<KeyRef<'a>>) -> Option<&mut PoolKey> {
+        let key = key.into();
+        self.keys.iter_mut().find(|k| k.matches(key))
+    }
+
+    pub async fn add_key(&mut self, name: &str) -> Result<&mut PoolKey, PoolError> {
+        if self.key(name).is_some() {
+            return Err(PoolError::DuplicateKey(KeyRef::Name(name).to_string()));
+        }
+        let key = PoolKey::open(&self.program, Some(name.into()), None, self.timeouts).await?;
+        self.keys.push(key);
+        Ok(self.keys.last_mut().unwrap())
+    }
+
+    pub fn remove_key<'a>(&mut self, key: impl Into<KeyRef<'a>>) -> Option<PoolKey> {
+        let key = key.into();
+        let pos = self.keys.iter().position(|k| k.matches(key))?;
+        Some(self.keys.remove(pos))
+    }
+
+    pub async fn authenticate<'a>(
+        &mut self,
+        key: impl Into<KeyRef<'a>>,
+        authn: AuthnInput,
+        prompt: impl FnMut(AuthnMode, Option<&str>) -> Option<AuthnInput> + Send + 'static,
+    ) -> Result<&mut SupervisedPlugin, PoolError> {
+        let key = key.into();
+        let Some(entry) = self.keys.iter_mut().find(|k| k.matches(key)) else {
+            return Err(PoolError::UnknownKey(key.to_string()));
+        };
+        if !entry.is_authenticated() {
+            let selected = match mem::replace(&mut entry.instance, Instance::Closed) {
+                Instance::Selected(plugin) => plugin,
+                _ => select(&self.program, entry.name.as_deref(), self.timeouts).await?,
+            };
+            let plugin = SupervisedPlugin::from_selected(
+                self.program.clone(),
+                entry.name.clone(),
+                selected,
+                authn,
+                Box::new(prompt),
+            )
+            .await?;
+            entry.set_public_key(plugin.public_key().to_vec());
+            entry.instance = Instance::Authenticated(plugin);
+        }
+        Ok(entry.plugin_mut().unwrap())
+    }
+
+    pub fn plugin_mut<'a>(
+        &mut self,
+        key: impl Into<KeyRef<'a>>,
+    ) -> Result<&mut SupervisedPlugin, PoolError> {
+        let key = key.into();
+        let Some(entry) = self.keys.iter_mut().find(|k| k.matches(key)) else {
+            return Err(PoolError::UnknownKey(key.to_string()));
+        };
+        let name = entry.key_ref().to_string();
+        entry.plugin_mut().ok_or(PoolError::NotAuthenticated(name))
+    }
+
+    pub async fn shutdown(self, grace: Duration) -> io::Result<()> {
+        let results = join_all(self.keys.into_iter().map(async |key| match key.instance {
+            Instance::Selected(plugin) => plugin.shutdown(grace).await.map(drop),
+            Instance::Authenticated(plugin) => plugin.shutdown(grace).await.map(drop),
+            Instance::Closed => Ok(()),
+        }))
+        .await;
+        results.into_iter().collect()
+    }
+}
+
+// Each key needs its own plugin process, so these drive a shell script that answers requests with
+// canned lines. Key "b" only reveals its public key once authenticated.
+#[cfg(all(test, unix, feature = "testing"))]
+mod tests {
+    use ic_auth_plugin_types::{
+        AuthenticateError, AuthenticateResponse, DescribeAuthnModeError, DescribeAuthnModeResponse,
+        GetPublicKeyError, GetPublicKeyResponse, Greeting, ListSelectableKeysResponse,
+        ProtocolVersion,
+    };
+
+    use super::*;
+    use crate::{MockKey, ScriptPlugin};
+
+    const SCRIPT: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+cat "$dir/greeting"
+key=
+authenticated=
+while read -r line; do
+    case "$line" in
+        *'"list-selectable-keys"'*) cat "$dir/keys" ;;
+        *'"select-key"'*) key=$(echo "$line" | sed 's/.*"key":"\([^"]*\)".*/\1/'); cat "$dir/ok" ;;
+        *'"describe-authn-mode"'*) cat "$dir/authn-mode" ;;
+        *'"authenticate"'*) authenticated=1; cat "$dir/ok" ;;
+        *'"get-public-key"'*)
+            if [ "$key" = b ] && [ -z "$authenticated" ]; then
+                cat "$dir/requires-authn"
+            else
+                cat "$dir/public-key-$key"
+            fi ;;
+        *) exit 1 ;;
+    esac
+done
+"#;
+
+    fn script(keys: &[MockKey]) -> ScriptPlugin {
+        let plugin = ScriptPlugin::new("pool", SCRIPT);
+        plugin.write(
+            "greeting",
+            &Greeting {
+                v: vec![ProtocolVersion::V1],
+                select: Some(SelectMode::Supported),
+                abort: None,
+            },
+        );
+        plugin.write(
+            "keys",
+            &Ok::<_, ListSelectableKeysError>(ListSelectableKeysResponse {
+                keys: keys.iter().map(|key| key.name().to_owned()).collect(),
+                exhaustive: false,
+            }),
+        );
+        plugin.write("ok", &Ok::<_, AuthenticateError>(AuthenticateResponse {}));
+        plugin.write(
+            "authn-mode",
+            &Ok::<_, DescribeAuthnModeError>(DescribeAuthnModeResponse {
+                mode: AuthnMode::Automatic,
+                value: None,
+            }),
+        );
+        plugin.write(
+            "requires-authn",
+            &Err::<GetPublicKeyResponse, _>(GetPublicKeyError::RequiresAuthn),
+        );
+        for key in keys {
+            plugin.write(
+                &format!("public-key-{}", key.name()),
+                &Ok::<_, GetPublicKeyError>(GetPublicKeyResponse {
+                    public_key_der: key.public_key_der().into(),
+                }),
+            );
+        }
+        plugin
+    }
+
+    #[tokio::test]
+    async fn opens_an_instance_per_listed_key() {
+        let keys = [
+            MockKey::from_seed("a", [1; 32]),
+            MockKey::from_seed("b", [2; 32]),
+        ];
+        let script = script(&keys);
+        let mut pool = PluginPool::launch(script.path()).await.unwrap();
+        assert_eq!(pool.select_mode(), SelectMode::Supported);
+        assert!(!pool.is_exhaustive());
+        let names: Vec<_> = pool.keys().map(PoolKey::name).collect();
+        assert_eq!(names, [Some("a"), Some("b")]);
+        assert_eq!(
+            pool.key("a").unwrap().principal(),
+            Some(keys[0].principal())
+        );
+        assert_eq!(pool.key("b").unwrap().principal(), None);
+        assert!(matches!(
+            pool.plugin_mut("b"),
+            Err(PoolError::NotAuthenticated(_))
+        ));
+        let plugin = pool
+            .authenticate("b", AuthnInput::default(), |_, _| None)
+            .await
+            .unwrap();
+        assert_eq!(plugin.public_key(), keys[1].public_key_der());
+        assert!(pool.key(keys[1].principal()).unwrap().is_authenticated());
+        pool.shutdown(Duration::from_secs(1)).await.unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ic_agent::identity::DelegatedIdentity;
+use ic_agent::{Identity, Signature};
+use ic_auth_plugin_types::SignDelegationError;
+use ic_principal::Principal;
+use ic_transport_types::{Delegation, EnvelopeContent};
+
+use crate::PluginIdentity;
+use crate::delegation::now;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CanisterScope {
+    // Requests wildcard delegations. If the plugin needs canister scoping, delegations are instead
+    // scoped to every canister signed for so far, and re-requested whenever a new canister appears.
+    Wildcard,
+    // Requests delegations scoped to exactly these canisters. Messages to other canisters are signed
+    // by the plugin directly.
+    Canisters(Vec<Principal>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SessionConfig {
+    pub lifetime: Duration,
+    pub refresh_margin: Duration,
+    pub scope: CanisterScope,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::from_secs(24 * 60 * 60),
+            refresh_margin: Duration::from_secs(5 * 60),
+            scope: CanisterScope::Wildcard,
+        }
+    }
+}
+
+// Signs envelopes with a local session key, delegated to by the plugin, so that only the delegation
+// itself has to go through the plugin. The delegation is re-requested shortly before it expires. If
+// the plugin cannot sign delegations at all, or not for a particular canister, envelopes are signed
+// by the plugin directly; the sender is the same either way.
+//
+// Refreshing holds the session lock for the whole plugin round-trip, so concurrent signers wait for
+// it rather than each requesting (and perhaps prompting the user for) a delegation of their own.
+pub struct SessionIdentity {
+    plugin: PluginIdentity,
+    config: SessionConfig,
+    state: Mutex<SessionState>,
+    // The current time in nanoseconds, replaceable in tests to expire sessions without waiting.
+    clock: fn() -> u64,
+}
+
+#[derive(Default)]
+struct SessionState {
+    session: Option<Session>,
+    unsupported: bool,
+    // Set under `CanisterScope::Wildcard` once the plugin has reported `NeedsCanisterScoping`.
+    learned: Option<Vec<Principal>>,
+    rejected: Vec<Principal>,
+    // Set once a call or query has been signed directly for lack of scope, since a scoped session
+    // might then not cover the corresponding read_state requests.
+    missed: bool,
+}
+
+struct Session {
+    identity: DelegatedIdentity,
+    canisters: Option<Vec<Principal>>,
+    expiry: u64,
+    refresh_at: u64,
+}
+
+impl SessionIdentity {
+    pub fn new(plugin: PluginIdentity, config: SessionConfig) -> Self {
+        Self {
+            plugin,
+            config,
+            state: Mutex::new(SessionState::default()),
+            clock: now,
+        }
+    }
+
+    pub fn plugin(&self) -> &PluginIdentity {
+        &self.plugin
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn into_plugin(self) -> PluginIdentity {
+        self.plugin
+    }
+
+    fn desired_canisters(&self, state: &SessionState) -> Option<Vec<Principal>> {
+        match &self.config.scope {
+            CanisterScope::Canisters(canisters) => Some(
+                canisters
+                    .iter()
+                    .filter(|canister| !state.rejected.contains(canister))
+                    .copied()
+                    .collect(),
+            ),
+            CanisterScope::Wildcard => state.learned.clone(),
+        }
+    }
+
+    // Returns the session to sign with, or `None` if the envelope should be signed by the plugin.
+    fn session<'a>(
+        &self,
+        state: &'a mut SessionState,
+        canister: Option<Principal>,
+    ) -> Result<Option<&'a DelegatedIdentity>, String> {
+        if let Some(canister) = canister {
+            let in_scope = !state.rejected.contains(&canister)
+                && match &self.config.scope {
+                    CanisterScope::Canisters(canisters) => canisters.contains(&canister),
+                    CanisterScope::Wildcard => true,
+                };
+            if !in_scope {
+                state.missed = true;
+                return Ok(None);
+            }
+            if let Some(learned) = &mut state.learned {
+                if !learned.contains(&canister) {
+                    learned.push(canister);
+                }
+            }
+        }
+        loop {
+            if state.unsupported {
+                return Ok(None);
+            }
+            let desired = self.desired_canisters(state);
+            let now = (self.clock)();
+            if let Some(session) = &state.session {
+                if session.canisters == desired && now < session.refresh_at {
+                    break;
+                }
+            }
+            if desired.as_ref().is_some_and(Vec::is_empty) {
+                state.session = None;
+                return Ok(None);
+            }
+            let desired_expiry = now.saturating_add(self.config.lifetime.as_nanos() as u64);
+            let err = match self
+                .plugin
+                .delegated_identity(desired_expiry, desired.as_deref())
+            {
+                Ok(identity) => {
+                    let expiry = identity
+                        .delegation_chain()
+                        .last()
+                        .map_or(0, |delegation| delegation.delegation.expiration);
+                    let margin = (self.config.refresh_margin.as_nanos() as u64)
+                        .min(expiry.saturating_sub(now) / 2);
+                    state.session = Some(Session {
+                        identity,
+                        canisters: desired,
+                        expiry,
+                        refresh_at: expiry - margin,
+                    });
+                    break;
+                }
+                Err(err) => err,
+            };
+            match err.rejection() {
+                Some(SignDelegationError::Unsupported) => {
+                    state.unsupported = true;
+                    state.session = None;
+                }
+                Some(SignDelegationError::NeedsCanisterScoping)
+                    if desired.is_none() && state.learned.is_none() =>
+                {
+                    state.learned = Some(canister.into_iter().collect());
+                }
+                Some(SignDelegationError::UnsupportedCanister { principals, .. })
+                    if principals
+                        .iter()
+                        .any(|p| desired.as_ref().is_some_and(|d| d.contains(p))) =>
+                {
+                    state.rejected.extend(principals);
+                    if let Some(learned) = &mut state.learned {
+                        learned.retain(|canister| !principals.contains(canister));
+                    }
+                    if canister.is_some_and(|canister| principals.contains(&canister)) {
+                        state.missed = true;
+                        return Ok(None);
+                    }
+                }
+                // A failed refresh is not fatal while the current delegation is still valid.
+                _ => match &state.session {
+                    Some(session) if session.canisters == desired && now < session.expiry => break,
+                    _ => return Err(format!("failed to refresh session delegation: {err}")),
+                },
+            }
+        }
+        let session = state.session.as_ref().unwrap();
+        if canister.is_none() && state.missed && session.canisters.is_some() {
+            return Ok(None);
+        }
+        Ok(Some(&session.identity))
+    }
+}
+
+impl Identity for SessionIdentity {
+    fn sender(&self) -> Result<Principal, String> {
+        self.plugin.sender()
+    }
+    fn public_key(&self) -> Option<Vec<u8>> {
+        self.plugin.public_key()
+    }
+    fn sign(&self, content: &EnvelopeContent) -> Result<Signature, String> {
+        let canister = match content {
+            EnvelopeContent::Call { canister_id, .. }
+            | EnvelopeContent::Query { canister_id, .. } => Some(*canister_id),
+            EnvelopeContent::ReadState { .. } => None,
+        };
+        let mut state = self.state.lock().unwrap();
+        match self.session(&mut state, canister)? {
+            Some(session) => session.sign(content),
+            None => {
+                drop(state);
+                self.plugin.sign(content)
+            }
+        }
+    }
+    fn sign_arbitrary(&self, content: &[u8]) -> Result<Signature, String> {
+        self.plugin.sign_arbitrary(content)
+    }
+    fn sign_delegation(&self, content: &Delegation) -> Result<Signature, String> {
+        self.plugin.sign_delegation(content)
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use ic_auth_plugin_types::Action;
+
+    use super::*;
+    use crate::{MockKey, MockPlugin};
+
+    fn query(canister: Principal, sender: Principal) -> EnvelopeContent {
+        EnvelopeContent::Query {
+            ingress_expiry: now() + 60_000_000_000,
+            sender,
+            canister_id: canister,
+            method_name: "greet".into(),
+            arg: vec![],
+            nonce: None,
+        }
+    }
+
+    async fn identity(mock: &MockPlugin) -> PluginIdentity {
+        mock.open()
+            .await
+            .unwrap()
+            .skip_key_selection()
+            .unwrap()
+            .authenticate(None, None)
+            .await
+            .unwrap()
+            .into_identity()
+            .await
+            .unwrap()
+    }
+
+    // How far the clock of the refresh test runs ahead of the real one.
+    static SKEW: AtomicU64 = AtomicU64::new(0);
+
+    fn skewed_now() -> u64 {
+        now() + SKEW.load(Ordering::Relaxed)
+    }
+
+    // The mock is served by the test's runtime, which has to keep running while the identity blocks.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn refreshes_the_delegation_before_it_expires() {
+        let key = MockKey::new("a");
+        let mock = MockPlugin::new(key.clone());
+        let config = SessionConfig {
+            lifetime: Duration::from_secs(60 * 60),
+            ..SessionConfig::default()
+        };
+        let mut session = SessionIdentity::new(identity(&mock).await, config);
+        session.clock = skewed_now;
+        let canister = Principal::from_slice(&[1]);
+        let signature = session.sign(&query(canister, key.principal())).unwrap();
+        assert_eq!(signature.public_key, Some(key.public_key_der()));
+        assert_eq!(signature.delegations.map(|d| d.len()), Some(1));
+        session.sign(&query(canister, key.principal())).unwrap();
+        assert_eq!(mock.count(Action::SignDelegation), 1);
+        // Past the default five minute refresh margin, but before expiry.
+        SKEW.store(56 * 60 * 1_000_000_000, Ordering::Relaxed);
+        session.sign(&query(canister, key.principal())).unwrap();
+        assert_eq!(mock.count(Action::SignDelegation), 2);
+        mock.assert_not_received(Action::SignEnvelopes);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn signs_directly_without_delegation_support() {
+        let key = MockKey::new("a");
+        let mock = MockPlugin::new(key.clone());
+        mock.fail_next(SignDelegationError::Unsupported);
+        let session = SessionIdentity::new(identity(&mock).await, SessionConfig::default());
+        let canister = Principal::from_slice(&[1]);
+        for _ in 0..2 {
+            let signature = session.sign(&query(canister, key.principal())).unwrap();
+            assert!(signature.delegations.is_none());
+        }
+        assert_eq!(mock.count(Action::SignDelegation), 1);
+        assert_eq!(mock.count(Action::SignEnvelopes), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn does_not_use_expired_delegations() {
+        let key = MockKey::new("a");
+        let mock = MockPlugin::new(key.clone());
+        mock.set_max_expiry(Some(1));
+        let session = SessionIdentity::new(identity(&mock).await, SessionConfig::default());
+        let canister = Principal::from_slice(&[1]);
+        assert!(session.sign(&query(canister, key.principal())).is_err());
+    }
+}
@@ -0,0 +1,474 @@
+use std::convert::Infallible;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use ic_auth_plugin_types::{
+    AuthenticateError, AuthnMode, DescribeAuthnModeError, ExtensionError, GetPublicKeyError,
+    KeySelectError, SignArbitraryDataError, SignDelegationError, SignEnvelopesError,
+};
+use ic_principal::Principal;
+use ic_transport_types::EnvelopeContent;
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use crate::{
+    Authenticated, HandshakeError, KeySelected, Plugin, PluginError, Timeouts, Transcript,
+};
+
+// Rejected authentication is retried, with a fresh prompt or a fresh `automatic` attempt, until it
+// has failed this many times.
+const AUTHN_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AuthnInput {
+    pub integrated: Option<AuthnMode>,
+    pub value: Option<String>,
+}
+
+// Asked for fresh authentication input when the remembered input doesn't fit the plugin's current
+// authentication mode, or was rejected. Receives the mode and its value (URL or message). Returning
+// `None` gives up.
+pub type AuthnPrompt = Box<dyn FnMut(AuthnMode, Option<&str>) -> Option<AuthnInput> + Send>;
+
+#[derive(Error, Debug)]
+pub enum LaunchError {
+    #[error("failed to start plugin: {0}")]
+    Open(#[from] PluginError<Infallible>),
+    #[error("failed to select key: {0}")]
+    KeySelect(#[from] PluginError<KeySelectError>),
+    #[error("failed to query authentication mode: {0}")]
+    AuthnMode(#[from] PluginError<DescribeAuthnModeError>),
+    #[error("failed to authenticate: {0}")]
+    Authenticate(#[from] PluginError<AuthenticateError>),
+    #[error("failed to get public key: {0}")]
+    PublicKey(#[from] PluginError<GetPublicKeyError>),
+    #[error("authentication was cancelled")]
+    Cancelled,
+    #[error("plugin's public key changed after restarting")]
+    KeyChanged,
+    #[error("plugin was not launched by the supervisor and cannot be restarted")]
+    NotRestartable,
+    #[error(
+        "a plugin opened on a current-thread runtime cannot back a PluginIdentity, launch it with PluginIdentity::launch instead"
+    )]
+    CurrentThreadRuntime,
+}
+
+impl<S, E> From<HandshakeError<S, E>> for LaunchError
+where
+    LaunchError: From<PluginError<E>>,
+{
+    fn from(err: HandshakeError<S, E>) -> Self {
+        err.error.into()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SupervisorError<E> {
+    #[error(transparent)]
+    Plugin(#[from] PluginError<E>),
+    #[error("failed to restart plugin: {0}")]
+    Restart(Box<LaunchError>),
+}
+
+// Keeps a plugin running across authentication expiry. SPEC.md lets a plugin exit with status zero
+// when user authentication expires; the supervisor then relaunches it, repeats the handshake with
+// the remembered key and authentication input, checks that the key is unchanged, and retries the
+// request that observed the exit.
+pub struct SupervisedPlugin {
+    // `None` for a plugin opened elsewhere, which is not restarted.
+    program: Option<OsString>,
+    key: Option<String>,
+    authn: AuthnInput,
+    prompt: AuthnPrompt,
+    timeouts: Timeouts,
+    plugin: Plugin<Authenticated>,
+    public_key: Vec<u8>,
+    restarts: u64,
+    transcript: Option<Transcript>,
+}
+
+impl SupervisedPlugin {
+    pub async fn launch(
+        program: impl AsRef<OsStr>,
+        key: Option<&str>,
+        authn: AuthnInput,
+        prompt: impl FnMut(AuthnMode, Option<&str>) -> Option<AuthnInput> + Send + 'static,
+    ) -> Result<Self, LaunchError> {
+        Self::launch_with_timeouts(program, key, authn, prompt, Timeouts::default()).await
+    }
+
+    pub async fn launch_with_timeouts(
+        program: impl AsRef<OsStr>,
+        key: Option<&str>,
+        mut authn: AuthnInput,
+        mut prompt: impl FnMut(AuthnMode, Option<&str>) -> Option<AuthnInput> + Send + 'static,
+        timeouts: Timeouts,
+    ) -> Result<Self, LaunchError> {
+        let program = program.as_ref().to_owned();
+        let key = key.map(String::from);
+        let plugin = select(&program, key.as_deref(), timeouts).await?;
+        let mut plugin = authenticate(plugin, &mut authn, &mut prompt).await?;
+        let public_key = plugin.public_key().await?;
+        Ok(Self {
+            program: Some(program),
+            key,
+            authn,
+            prompt: Box::new(prompt),
+            timeouts,
+            plugin,
+            public_key,
+            restarts: 0,
+            transcript: None,
+        })
+    }
+
+    // Finishes the handshake for an already opened instance of `program` with `key` selected.
+    pub(crate) async fn from_selected(
+        program: OsString,
+        key: Option<String>,
+        plugin: Plugin<KeySelected>,
+        mut authn: AuthnInput,
+        mut prompt: AuthnPrompt,
+    ) -> Result<Self, LaunchError> {
+        let timeouts = plugin.timeouts();
+        let mut plugin = authenticate(plugin, &mut authn, &mut prompt).await?;
+        let public_key = plugin.public_key().await?;
+        Ok(Self {
+            program: Some(program),
+            key,
+            authn,
+            prompt,
+            timeouts,
+            plugin,
+            public_key,
+            restarts: 0,
+            transcript: None,
+        })
+    }
+
+    // Wraps a plugin that was opened and authenticated by other means, such as over a transport.
+    // Requests to it are passed through as-is, and it fails with `LaunchError::NotRestartable` if it
+    // exits for authentication expiry.
+    pub async fn adopt(
+        mut plugin: Plugin<Authenticated>,
+    ) -> Result<Self, PluginError<GetPublicKeyError>> {
+        let public_key = plugin.public_key().await?;
+        Ok(Self {
+            program: None,
+            key: None,
+            authn: AuthnInput::default(),
+            prompt: Box::new(|_, _| None),
+            timeouts: plugin.timeouts(),
+            plugin,
+            public_key,
+            restarts: 0,
+            transcript: None,
+        })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn restarts(&self) -> u64 {
+        self.restarts
+    }
+
+    pub fn plugin_mut(&mut self) -> &mut Plugin<Authenticated> {
+        &mut self.plugin
+    }
+
+    // Records the current plugin and every plugin it is restarted as. Since a transcript only starts
+    // when attached, the restarted plugins' handshakes are recorded but the first one's is not.
+    pub fn record(&mut self, transcript: Transcript) {
+        self.plugin.record(transcript.clone());
+        self.transcript = Some(transcript);
+    }
+
+    pub async fn restart(&mut self) -> Result<(), LaunchError> {
+        let program = self.program.as_ref().ok_or(LaunchError::NotRestartable)?;
+        let mut plugin = select(program, self.key.as_deref(), self.timeouts).await?;
+        if let Some(transcript) = &self.transcript {
+            plugin.record(transcript.clone());
+        }
+        let mut plugin = authenticate(plugin, &mut self.authn, &mut self.prompt).await?;
+        if plugin.public_key().await? != self.public_key {
+            return Err(LaunchError::KeyChanged);
+        }
+        self.plugin = plugin;
+        self.restarts += 1;
+        Ok(())
+    }
+
+    pub async fn sign_envelopes(
+        &mut self,
+        envelopes: &[EnvelopeContent],
+    ) -> Result<Vec<Vec<u8>>, SupervisorError<SignEnvelopesError>> {
+        self.with_restart(async |plugin| plugin.sign_envelopes(envelopes).await)
+            .await
+    }
+
+    pub async fn sign_delegation(
+        &mut self,
+        public_key_der: &[u8],
+        desired_expiry: u128,
+        desired_canisters: Option<&[Principal]>,
+    ) -> Result<(Vec<u8>, u128), SupervisorError<SignDelegationError>> {
+        self.with_restart(async |plugin| {
+            plugin
+                .sign_delegation(public_key_der, desired_expiry, desired_canisters)
+                .await
+        })
+        .await
+    }
+
+    pub async fn sign_arbitrary(
+        &mut self,
+        data: &[u8],
+    ) -> Result<Vec<u8>, SupervisorError<SignArbitraryDataError>> {
+        self.with_restart(async |plugin| plugin.sign_arbitrary(data).await)
+            .await
+    }
+
+    pub async fn extension_request(
+        &mut self,
+        extension: &str,
+        action: &str,
+        payload: Map<String, Value>,
+    ) -> Result<Map<String, Value>, SupervisorError<ExtensionError>> {
+        self.with_restart(async |plugin| {
+            plugin
+                .extension_request(extension, action, payload.clone())
+                .await
+        })
+        .await
+    }
+
+    pub async fn shutdown(self, grace: Duration) -> io::Result<Option<ExitStatus>> {
+        self.plugin.shutdown(grace).await
+    }
+
+    async fn with_restart<T, E>(
+        &mut self,
+        mut f: impl AsyncFnMut(&mut Plugin<Authenticated>) -> Result<T, PluginError<E>>,
+    ) -> Result<T, SupervisorError<E>> {
+        match f(&mut self.plugin).await {
+            Err(e) if e.is_authn_expiry() => {
+                self.restart()
+                    .await
+                    .map_err(|e| SupervisorError::Restart(Box::new(e)))?;
+                Ok(f(&mut self.plugin).await?)
+            }
+            res => Ok(res?),
+        }
+    }
+}
+
+pub(crate) async fn select(
+    program: &OsStr,
+    key: Option<&str>,
+    timeouts: Timeouts,
+) -> Result<Plugin<KeySelected>, LaunchError> {
+    let mut plugin = Plugin::open(program).await?;
+    plugin.set_timeouts(timeouts);
+    Ok(match key {
+        Some(key) => plugin.select_key(key).await?,
+        None => plugin.skip_key_selection()?,
+    })
+}
+
+async fn authenticate(
+    mut plugin: Plugin<KeySelected>,
+    authn: &mut AuthnInput,
+    prompt: &mut (impl FnMut(AuthnMode, Option<&str>) -> Option<AuthnInput> + ?Sized),
+) -> Result<Plugin<Authenticated>, LaunchError> {
+    let mut rejected = 0;
+    loop {
+        let (mode, value) = plugin.authn_mode().await?;
+        let input = if mode == AuthnMode::Automatic {
+            AuthnInput {
+                integrated: Some(AuthnMode::Automatic),
+                value: None,
+            }
+        } else if rejected == 0 && authn.integrated.is_none_or(|integrated| integrated == mode) {
+            authn.clone()
+        } else {
+            prompt(mode, value.as_deref()).ok_or(LaunchError::Cancelled)?
+        };
+        match plugin
+            .authenticate(input.integrated, input.value.clone())
+            .await
+        {
+            Ok(plugin) => {
+                if mode != AuthnMode::Automatic {
+                    *authn = input;
+                }
+                return Ok(plugin);
+            }
+            Err(HandshakeError {
+                plugin: retry,
+                error:
+                    PluginError::Plugin(AuthenticateError::BadMode | AuthenticateError::BadAuthn { .. }),
+            }) if rejected + 1 < AUTHN_ATTEMPTS => {
+                plugin = retry;
+                rejected += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Restarts are triggered by the plugin process exiting, which in-process mocks cannot do, so these
+// drive a shell script that plays back canned lines and exits once after authenticating.
+#[cfg(all(test, unix, feature = "testing"))]
+mod tests {
+    use ic_auth_plugin_types::{
+        Action, AuthenticateError, AuthenticateResponse, AuthnMode, DescribeAuthnModeError,
+        DescribeAuthnModeResponse, GetPublicKeyError, GetPublicKeyResponse, Greeting,
+        ProtocolVersion, SignArbitraryDataError, SignArbitraryDataResponse,
+    };
+
+    use super::*;
+    use crate::{MockAuthn, MockKey, ScriptPlugin};
+
+    const SCRIPT: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+cat "$dir/greeting"
+for response in authn-mode authenticate public-key; do
+    read -r line && echo "$line" >> "$dir/log" && cat "$dir/$response" || exit 1
+done
+read -r line && echo "$line" >> "$dir/log" || exit 1
+if [ ! -e "$dir/expired" ]; then
+    touch "$dir/expired"
+    exit 0
+fi
+cat "$dir/signature"
+read -r line
+"#;
+
+    fn script(name: &str, key: &MockKey) -> ScriptPlugin {
+        let plugin = ScriptPlugin::new(name, SCRIPT);
+        plugin.write(
+            "greeting",
+            &Greeting {
+                v: vec![ProtocolVersion::V1],
+                select: None,
+                abort: None,
+            },
+        );
+        plugin.write(
+            "authn-mode",
+            &Ok::<_, DescribeAuthnModeError>(DescribeAuthnModeResponse {
+                mode: AuthnMode::Password,
+                value: None,
+            }),
+        );
+        plugin.write(
+            "authenticate",
+            &Ok::<_, AuthenticateError>(AuthenticateResponse {}),
+        );
+        plugin.write(
+            "public-key",
+            &Ok::<_, GetPublicKeyError>(GetPublicKeyResponse {
+                public_key_der: key.public_key_der().into(),
+            }),
+        );
+        plugin.write(
+            "signature",
+            &Ok::<_, SignArbitraryDataError>(SignArbitraryDataResponse {
+                signature: key.sign(b"data").into(),
+            }),
+        );
+        plugin
+    }
+
+    #[tokio::test]
+    async fn restarts_after_authentication_expiry() {
+        let key = MockKey::from_seed("a", [1; 32]);
+        let script = script("restart", &key);
+        let authn = AuthnInput {
+            integrated: Some(AuthnMode::Password),
+            value: Some("hunter2".into()),
+        };
+        let mut plugin = SupervisedPlugin::launch(script.path(), None, authn, |_, _| {
+            panic!("remembered password was not reused")
+        })
+        .await
+        .unwrap();
+        assert_eq!(plugin.public_key(), key.public_key_der());
+        assert_eq!(
+            plugin.sign_arbitrary(b"data").await.unwrap(),
+            key.sign(b"data")
+        );
+        assert_eq!(plugin.restarts(), 1);
+        let log = script.read("log");
+        assert_eq!(log.matches("hunter2").count(), 2, "{log}");
+        assert_eq!(log.matches("sign-arbitrary-data").count(), 2, "{log}");
+    }
+
+    #[tokio::test]
+    async fn refuses_a_restart_with_another_key() {
+        let key = MockKey::from_seed("a", [1; 32]);
+        let script = script("key-changed", &key);
+        let authn = AuthnInput {
+            integrated: Some(AuthnMode::Password),
+            value: Some("hunter2".into()),
+        };
+        let mut plugin = SupervisedPlugin::launch(script.path(), None, authn, |_, _| None)
+            .await
+            .unwrap();
+        script.write(
+            "public-key",
+            &Ok::<_, GetPublicKeyError>(GetPublicKeyResponse {
+                public_key_der: MockKey::from_seed("b", [2; 32]).public_key_der().into(),
+            }),
+        );
+        assert!(matches!(
+            plugin.sign_arbitrary(b"data").await,
+            Err(SupervisorError::Restart(e)) if matches!(*e, LaunchError::KeyChanged)
+        ));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_rejected_automatic_authentication() {
+        let mock = crate::MockPlugin::new(MockKey::new("a"));
+        mock.script_authn([MockAuthn {
+            mode: AuthnMode::Automatic,
+            value: None,
+            expect: Some("never sent".into()),
+        }]);
+        let plugin = mock.open().await.unwrap().skip_key_selection().unwrap();
+        let mut prompt = |_: AuthnMode, _: Option<&str>| -> Option<AuthnInput> {
+            panic!("automatic authentication prompted")
+        };
+        assert!(matches!(
+            authenticate(plugin, &mut AuthnInput::default(), &mut prompt).await,
+            Err(LaunchError::Authenticate(PluginError::Plugin(
+                AuthenticateError::BadAuthn { .. }
+            )))
+        ));
+        assert_eq!(mock.count(Action::Authenticate), AUTHN_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn adopted_plugins_are_not_restarted() {
+        let mock = crate::MockPlugin::new(MockKey::new("a"));
+        let plugin = mock
+            .open()
+            .await
+            .unwrap()
+            .skip_key_selection()
+            .unwrap()
+            .authenticate(None, None)
+            .await
+            .unwrap();
+        let mut plugin = SupervisedPlugin::adopt(plugin).await.unwrap();
+        assert!(matches!(
+            plugin.restart().await,
+            Err(LaunchError::NotRestartable)
+        ));
+    }
+}
@@ -0,0 +1,309 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader, DuplexStream};
+
+use crate::{Transport, TransportReader, TransportWriter};
+
+const REDACTED: &str = "[redacted]";
+
+// One line of a transcript file, which is itself line-delimited JSON. `at` is in milliseconds since
+// the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    pub at: u64,
+    pub from: Side,
+    pub line: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Side {
+    Host,
+    Plugin,
+}
+
+#[derive(Error, Debug)]
+pub enum TranscriptError {
+    #[error("transcript I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed transcript entry on line {line}: {source}")]
+    Malformed {
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+// Where a replayed session stopped following its transcript. `expected` is `None` if the host sent
+// a line after the transcript ended.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+#[error("host sent {actual:?} at entry {index}, but the transcript has {expected:?}")]
+pub struct Divergence {
+    pub index: usize,
+    pub expected: Option<String>,
+    pub actual: String,
+}
+
+// A sink for the lines a plugin connection exchanges. Clones append to the same file, so one
+// transcript can cover several plugins (e.g. across supervisor restarts); entries are flushed as
+// they are written so that a crashing host still leaves a usable transcript. Secrets the host sends,
+// such as passwords passed to `authenticate`, are replaced with a placeholder.
+#[derive(Clone)]
+pub struct Transcript {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Transcript {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn new(sink: impl Write + Send + 'static) -> Self {
+        Self {
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+        }
+    }
+
+    pub(crate) fn record(&self, from: Side, line: &str) {
+        self.record_at(now_millis(), from, line);
+    }
+
+    // Recording is best-effort: a full disk should not break the plugin connection it observes.
+    pub(crate) fn record_at(&self, at: u64, from: Side, line: &str) {
+        let line = match from {
+            Side::Host => redact(line),
+            Side::Plugin => line.to_owned(),
+        };
+        let Ok(mut entry) = serde_json::to_string(&Entry { at, from, line }) else {
+            return;
+        };
+        entry.push('\n');
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = sink.write_all(entry.as_bytes()).and_then(|()| sink.flush());
+    }
+}
+
+// A fake plugin that plays back a recorded transcript. Each connection answers with the recorded
+// plugin lines in order, as long as the host makes the same requests as the recorded host; on the
+// first request with a different action, the connection is closed and the divergence is kept for
+// inspection. Recorded timing is not reproduced.
+#[derive(Clone)]
+pub struct Replay {
+    entries: Arc<[Entry]>,
+    divergence: Arc<Mutex<Option<Divergence>>>,
+}
+
+impl Replay {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TranscriptError> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    pub fn parse(reader: impl BufRead) -> Result<Self, TranscriptError> {
+        let mut entries = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry =
+                serde_json::from_str(&line).map_err(|source| TranscriptError::Malformed {
+                    line: n + 1,
+                    source,
+                })?;
+            entries.push(entry);
+        }
+        Ok(Self::from_entries(entries))
+    }
+
+    pub fn from_entries(entries: Vec<Entry>) -> Self {
+        Self {
+            entries: entries.into(),
+            divergence: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    // The first divergence of any connection made from this replay or its clones.
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.divergence_slot().clone()
+    }
+
+    // Serves a new connection on a background task of the current tokio runtime.
+    pub fn connect(&self) -> DuplexStream {
+        let (host, plugin) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(self.clone().serve(plugin));
+        host
+    }
+
+    async fn serve(self, stream: DuplexStream) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = AsyncBufReader::new(reader).lines();
+        for (index, entry) in self.entries.iter().enumerate() {
+            match entry.from {
+                Side::Plugin => {
+                    let written = async {
+                        writer.write_all(entry.line.as_bytes()).await?;
+                        writer.write_all(b"\n").await?;
+                        writer.flush().await
+                    };
+                    if written.await.is_err() {
+                        return;
+                    }
+                }
+                Side::Host => {
+                    let Ok(Some(actual)) = lines.next_line().await else {
+                        return;
+                    };
+                    if !same_request(&entry.line, &actual) {
+                        self.diverge(Divergence {
+                            index,
+                            expected: Some(entry.line.clone()),
+                            actual: redact(&actual),
+                        });
+                        return;
+                    }
+                }
+            }
+        }
+        if let Ok(Some(actual)) = lines.next_line().await {
+            self.diverge(Divergence {
+                index: self.entries.len(),
+                expected: None,
+                actual: redact(&actual),
+            });
+        }
+    }
+
+    fn diverge(&self, divergence: Divergence) {
+        self.divergence_slot().get_or_insert(divergence);
+    }
+
+    fn divergence_slot(&self) -> MutexGuard<'_, Option<Divergence>> {
+        self.divergence.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Transport for Replay {
+    fn into_split(self) -> (TransportReader, TransportWriter) {
+        self.connect().into_split()
+    }
+}
+
+// Requests usually embed the time (ingress and delegation expiries), so a replayed host cannot send
+// byte-identical lines; matching the action is what keeps the replay in step.
+fn same_request(recorded: &str, actual: &str) -> bool {
+    match (action(recorded), action(actual)) {
+        (Some(recorded), Some(actual)) => recorded == actual,
+        _ => recorded == actual,
+    }
+}
+
+fn action(line: &str) -> Option<String> {
+    let Ok(Value::Object(mut req)) = serde_json::from_str::<Value>(line) else {
+        return None;
+    };
+    match req.remove("action")? {
+        Value::String(action) => Some(action),
+        _ => None,
+    }
+}
+
+fn redact(line: &str) -> String {
+    let Ok(Value::Object(mut req)) = serde_json::from_str::<Value>(line) else {
+        return line.to_owned();
+    };
+    if req.get("action").and_then(Value::as_str) != Some("authenticate") {
+        return line.to_owned();
+    }
+    match req.get_mut("value") {
+        Some(value) if !value.is_null() => *value = REDACTED.into(),
+        _ => return line.to_owned(),
+    }
+    serde_json::to_string(&req).unwrap_or_else(|_| line.to_owned())
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_millis() as u64)
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use ic_auth_plugin_types::{AuthnMode, SelectMode};
+
+    use super::*;
+    use crate::{MockAuthn, MockKey, MockPlugin, Plugin};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn handshake(plugin: Plugin, key: &str) -> Vec<u8> {
+        let mut plugin = plugin
+            .select_key(key)
+            .await
+            .unwrap()
+            .authenticate(Some(AuthnMode::Password), Some("hunter2".into()))
+            .await
+            .unwrap();
+        plugin.sign_arbitrary(b"data").await.unwrap()
+    }
+
+    async fn record() -> (Replay, Vec<u8>) {
+        let mock = MockPlugin::with_keys(SelectMode::Supported, vec![MockKey::new("a")]);
+        mock.script_authn([MockAuthn {
+            mode: AuthnMode::Password,
+            value: None,
+            expect: Some("hunter2".into()),
+        }]);
+        let buffer = Buffer::default();
+        let mut plugin = mock.open().await.unwrap();
+        plugin.record(Transcript::new(buffer.clone()));
+        let signature = handshake(plugin, "a").await;
+        let replay = Replay::parse(&buffer.0.lock().unwrap()[..]).unwrap();
+        (replay, signature)
+    }
+
+    #[tokio::test]
+    async fn replays_a_recorded_session() {
+        let (replay, signature) = record().await;
+        let sides: Vec<_> = replay.entries().iter().map(|entry| entry.from).collect();
+        assert_eq!(sides.first(), Some(&Side::Plugin));
+        assert_eq!(sides.len(), 7);
+        assert!(!replay.entries().iter().any(|e| e.line.contains("hunter2")));
+        assert!(replay.entries().iter().any(|e| e.line.contains(REDACTED)));
+        let plugin = Plugin::connect(replay.clone()).await.unwrap();
+        assert_eq!(handshake(plugin, "a").await, signature);
+        assert_eq!(replay.divergence(), None);
+    }
+
+    #[tokio::test]
+    async fn reports_where_a_replay_diverges() {
+        let (replay, _) = record().await;
+        let plugin = Plugin::connect(replay.clone()).await.unwrap();
+        let plugin = plugin.skip_key_selection().unwrap();
+        let mut plugin = plugin.into_dynamic();
+        assert!(plugin.authn_mode().await.is_err());
+        let divergence = replay.divergence().unwrap();
+        assert_eq!(divergence.index, 1);
+        assert!(divergence.actual.contains("describe-authn-mode"));
+    }
+}
@@ -0,0 +1,90 @@
+use tokio::io::{self, AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+pub type TransportReader = Box<dyn AsyncRead + Send + Sync + Unpin>;
+pub type TransportWriter = Box<dyn AsyncWrite + Send + Sync + Unpin>;
+
+// A connection to a plugin that is already running, such as a local signing daemon. The protocol is
+// the same line-delimited JSON that `Plugin::open` speaks over a child process's stdin and stdout;
+// shutting down the writer is the signal to shut down gracefully.
+pub trait Transport {
+    fn into_split(self) -> (TransportReader, TransportWriter);
+}
+
+impl Transport for TcpStream {
+    fn into_split(self) -> (TransportReader, TransportWriter) {
+        let (reader, writer) = TcpStream::into_split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn into_split(self) -> (TransportReader, TransportWriter) {
+        let (reader, writer) = UnixStream::into_split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+// In-memory pipes, e.g. for running a plugin on another task in tests.
+impl Transport for DuplexStream {
+    fn into_split(self) -> (TransportReader, TransportWriter) {
+        let (reader, writer) = io::split(self);
+        (Box::new(reader), Box::new(writer))
+    }
+}
+
+impl<R, W> Transport for (R, W)
+where
+    R: AsyncRead + Send + Sync + Unpin + 'static,
+    W: AsyncWrite + Send + Sync + Unpin + 'static,
+{
+    fn into_split(self) -> (TransportReader, TransportWriter) {
+        (Box::new(self.0), Box::new(self.1))
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::time::Duration;
+
+    use ic_auth_plugin_types::Action;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{MockKey, MockPlugin, Plugin};
+
+    // Bridges a TCP connection to a mock, so the client only ever sees the socket.
+    #[tokio::test]
+    async fn speaks_the_protocol_over_tcp() {
+        let key = MockKey::new("a");
+        let mock = MockPlugin::new(key.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn({
+            let mock = mock.clone();
+            async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut plugin = mock.connect();
+                let _ = tokio::io::copy_bidirectional(&mut socket, &mut plugin).await;
+            }
+        });
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut plugin = Plugin::connect(socket)
+            .await
+            .unwrap()
+            .skip_key_selection()
+            .unwrap()
+            .authenticate(None, None)
+            .await
+            .unwrap();
+        assert_eq!(plugin.public_key().await.unwrap(), key.public_key_der());
+        assert_eq!(plugin.id(), None);
+        let status = plugin.shutdown(Duration::from_secs(10)).await.unwrap();
+        assert!(status.is_none());
+        server.await.unwrap();
+        mock.assert_actions(&[Action::Authenticate, Action::GetPublicKey]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+pub(crate) enum Reply {
+    Line(String),
+    Exited(Option<ExitStatus>),
+    TimedOut,
+}
+
+// A plugin process driven line by line, so that the harness can send requests no well-behaved host
+// would, and observe whether the plugin answers, exits, or hangs.
+pub(crate) struct Instance {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    lines: Receiver<io::Result<String>>,
+}
+
+impl Instance {
+    pub(crate) fn launch(program: &OsStr) -> io::Result<Self> {
+        let mut child = Command::new(program)
+            .arg("--ic-auth-plugin")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, lines) = mpsc::channel();
+        thread::Builder::new()
+            .name("conformance-stdout".into())
+            .spawn(move || {
+                for line in BufReader::new(stdout).lines() {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self {
+            child,
+            stdin,
+            lines,
+        })
+    }
+
+    pub(crate) fn recv(&mut self, timeout: Duration) -> Reply {
+        match self.lines.recv_timeout(timeout) {
+            Ok(Ok(line)) => Reply::Line(line),
+            Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => Reply::Exited(self.wait(timeout)),
+            Err(RecvTimeoutError::Timeout) => Reply::TimedOut,
+        }
+    }
+
+    pub(crate) fn send_line(&mut self, line: &str) -> io::Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        writeln!(stdin, "{line}")?;
+        stdin.flush()
+    }
+
+    pub(crate) fn exchange(&mut self, line: &str, timeout: Duration) -> Reply {
+        if self.send_line(line).is_err() {
+            return Reply::Exited(self.wait(timeout));
+        }
+        self.recv(timeout)
+    }
+
+    // Sends a well-formed request and decodes the plugin's response as `Result<T, E>`.
+    pub(crate) fn request<T: DeserializeOwned, E: DeserializeOwned>(
+        &mut self,
+        req: &impl Serialize,
+        timeout: Duration,
+    ) -> Result<Result<T, E>, String> {
+        let line = serde_json::to_string(req).map_err(|e| format!("encoding request: {e}"))?;
+        match self.exchange(&line, timeout) {
+            Reply::Line(resp) => {
+                serde_json::from_str(&resp).map_err(|e| format!("malformed response {resp:?}: {e}"))
+            }
+            Reply::Exited(status) => Err(format!("plugin exited ({})", describe(status))),
+            Reply::TimedOut => Err(format!("no response within {}s", timeout.as_secs())),
+        }
+    }
+
+    pub(crate) fn close_stdin(&mut self) {
+        self.stdin = None;
+    }
+
+    pub(crate) fn wait(&mut self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.child.try_wait() {
+                Ok(Some(status)) => return Some(status),
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+pub(crate) fn describe(status: Option<ExitStatus>) -> String {
+    match status {
+        Some(status) => status.to_string(),
+        None => "did not exit".into(),
+    }
+}
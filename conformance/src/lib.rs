@@ -0,0 +1,816 @@
+use std::ffi::OsString;
+use std::fmt::{self, Display};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ic_auth_plugin_types::{
+    AuthenticateError, AuthenticateRequest, AuthenticateResponse, AuthnMode,
+    DescribeAuthnModeError, DescribeAuthnModeRequest, DescribeAuthnModeResponse, GetPublicKeyError,
+    GetPublicKeyRequest, GetPublicKeyResponse, Greeting, KeySelectError, KeySelectRequest,
+    KeySelectResponse, ListSelectableKeysError, ListSelectableKeysRequest,
+    ListSelectableKeysResponse, ProtocolVersion, Request, SelectMode, SignArbitraryDataError,
+    SignArbitraryDataRequest, SignArbitraryDataResponse, SignDelegationError,
+    SignDelegationRequest, SignDelegationResponse, SignEnvelopesError, SignEnvelopesRequest,
+    SignEnvelopesResponse,
+};
+use ic_certification::Label;
+use ic_principal::Principal;
+use ic_transport_types::{Delegation, EnvelopeContent};
+use serde_json::Value;
+
+use crate::instance::{Instance, Reply, describe};
+use crate::verify::PublicKey;
+
+mod instance;
+mod verify;
+
+// RFC 8410 SubjectPublicKeyInfo header for an Ed25519 key, followed by the 32 raw key bytes.
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+// The ledger canister; any valid canister ID would do, since nothing is sent to the IC.
+const CANISTER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub program: OsString,
+    // The key to select, if the plugin supports selection. Defaults to the first listed key.
+    pub key: Option<String>,
+    // Passed as an integrated password if the plugin asks for one. Otherwise the plugin is left to
+    // authenticate the user on its own.
+    pub password: Option<String>,
+    pub timeout: Duration,
+    // Authentication may wait on the user, so it gets a separate, longer timeout.
+    pub authn_timeout: Duration,
+}
+
+impl Options {
+    pub fn new(program: impl Into<OsString>) -> Self {
+        Self {
+            program: program.into(),
+            key: None,
+            password: None,
+            timeout: Duration::from_secs(10),
+            authn_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    Pass(Option<String>),
+    Fail(String),
+    Skip(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|check| matches!(check.outcome, Outcome::Fail(_)))
+    }
+
+    fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.checks.iter().filter(|check| f(&check.outcome)).count()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                Outcome::Pass(None) => writeln!(f, "PASS  {}", check.name)?,
+                Outcome::Pass(Some(note)) => writeln!(f, "PASS  {}: {note}", check.name)?,
+                Outcome::Fail(reason) => writeln!(f, "FAIL  {}: {reason}", check.name)?,
+                Outcome::Skip(reason) => writeln!(f, "SKIP  {}: {reason}", check.name)?,
+            }
+        }
+        write!(
+            f,
+            "\n{} passed, {} failed, {} skipped",
+            self.count(|o| matches!(o, Outcome::Pass(_))),
+            self.count(|o| matches!(o, Outcome::Fail(_))),
+            self.count(|o| matches!(o, Outcome::Skip(_))),
+        )
+    }
+}
+
+// Checks a plugin executable against SPEC.md: walks each phase of the handshake, exercises every
+// request, verifies the signatures it returns, makes sure it rejects malformed and out-of-order
+// requests, and checks that it shuts down when its stdin is closed.
+pub fn run(options: &Options) -> Report {
+    let mut harness = Harness {
+        options,
+        report: Report::default(),
+    };
+    harness.handshake();
+    harness.expect_rejection("malformed request", "this is not json");
+    harness.expect_rejection(
+        "unknown action",
+        r#"{"v":1,"action":"conformance-unknown-action"}"#,
+    );
+    harness.expect_rejection(
+        "out-of-order request",
+        r#"{"v":1,"action":"sign-envelopes","contents":[]}"#,
+    );
+    harness.report
+}
+
+const SIGNING_CHECKS: [&str; 7] = [
+    "get-public-key",
+    "sign-envelopes",
+    "sign-delegation",
+    "sign-delegation (scoped)",
+    "sign-arbitrary-data",
+    "concurrent instances",
+    "shutdown on stdin close",
+];
+
+struct Harness<'a> {
+    options: &'a Options,
+    report: Report,
+}
+
+struct Authenticated {
+    instance: Instance,
+    public_key_der: Vec<u8>,
+    principal: Principal,
+}
+
+impl Harness<'_> {
+    fn record(&mut self, name: &'static str, outcome: Outcome) {
+        self.report.checks.push(Check { name, outcome });
+    }
+
+    fn pass(&mut self, name: &'static str) {
+        self.record(name, Outcome::Pass(None));
+    }
+
+    fn fail(&mut self, name: &'static str, reason: impl Into<String>) {
+        self.record(name, Outcome::Fail(reason.into()));
+    }
+
+    fn skip(&mut self, name: &'static str, reason: impl Into<String>) {
+        self.record(name, Outcome::Skip(reason.into()));
+    }
+
+    fn skip_all(&mut self, names: &[&'static str], reason: &str) {
+        for name in names {
+            self.skip(name, reason);
+        }
+    }
+
+    fn launch(&self) -> Result<(Instance, Greeting), String> {
+        let mut instance = Instance::launch(&self.options.program)
+            .map_err(|e| format!("failed to launch plugin: {e}"))?;
+        let greeting = match instance.recv(self.options.timeout) {
+            Reply::Line(line) => serde_json::from_str::<Greeting>(&line)
+                .map_err(|e| format!("malformed greeting {line:?}: {e}"))?,
+            Reply::Exited(status) => {
+                return Err(format!(
+                    "plugin exited before greeting ({})",
+                    describe(status)
+                ));
+            }
+            Reply::TimedOut => return Err("no greeting received".into()),
+        };
+        if let Some(abort) = greeting.abort {
+            return Err(format!("plugin aborted: {abort}"));
+        }
+        if !greeting.supports(&ProtocolVersion::V1) {
+            return Err(format!(
+                "greeting does not list version 1: {:?}",
+                greeting.v
+            ));
+        }
+        Ok((instance, greeting))
+    }
+
+    fn handshake(&mut self) {
+        const ALL: [&str; 5] = [
+            "list-selectable-keys",
+            "select-key",
+            "describe-authn-mode",
+            "authenticate",
+            "get-public-key (before authn)",
+        ];
+        let (mut instance, greeting) = match self.launch() {
+            Ok(launched) => launched,
+            Err(e) => {
+                self.fail("greeting", e);
+                self.skip_all(&ALL, "no greeting");
+                self.skip_all(&SIGNING_CHECKS, "no greeting");
+                return;
+            }
+        };
+        let select_mode = greeting.select.unwrap_or(SelectMode::Unsupported);
+        let note = match greeting.select {
+            Some(_) => format!("key selection {}", mode_name(select_mode)),
+            None => "key selection not declared, assumed unsupported".into(),
+        };
+        self.record("greeting", Outcome::Pass(Some(note)));
+
+        let listed = self.list_keys(&mut instance, select_mode);
+        let key = match select_mode {
+            SelectMode::Unsupported => None,
+            _ => self.options.key.clone().or_else(|| {
+                listed
+                    .as_ref()
+                    .and_then(|listed| listed.keys.first().cloned())
+            }),
+        };
+        if let Some(listed) = &listed {
+            if listed.exhaustive {
+                self.reject_unlisted_key(listed);
+            }
+        }
+        match &key {
+            Some(key) => {
+                if let Err(e) = self.select_key(&mut instance, key) {
+                    self.fail("select-key", e);
+                    self.skip_all(&ALL[2..], "key selection failed");
+                    self.skip_all(&SIGNING_CHECKS, "key selection failed");
+                    return;
+                }
+                self.record("select-key", Outcome::Pass(Some(format!("selected {key}"))));
+            }
+            None if select_mode == SelectMode::Required => {
+                self.fail(
+                    "select-key",
+                    "plugin requires key selection but listed no keys; pass --key",
+                );
+                self.skip_all(&ALL[2..], "no key selected");
+                self.skip_all(&SIGNING_CHECKS, "no key selected");
+                return;
+            }
+            None => self.skip("select-key", "plugin does not support key selection"),
+        }
+
+        let pre_authn_key = self.public_key_before_authn(&mut instance);
+        let authenticated = match self.authenticate(&mut instance) {
+            Ok(()) => self.public_key(instance, pre_authn_key),
+            Err(e) => {
+                self.fail("authenticate", e);
+                self.skip_all(&SIGNING_CHECKS, "authentication failed");
+                return;
+            }
+        };
+        let Some(mut authenticated) = authenticated else {
+            self.skip_all(&SIGNING_CHECKS[1..], "no valid public key");
+            return;
+        };
+        let public_key = match PublicKey::from_der(&authenticated.public_key_der) {
+            Ok(public_key) => public_key,
+            Err(_) => unreachable!("checked by get-public-key"),
+        };
+        self.sign_envelopes(&mut authenticated, &public_key);
+        self.sign_delegation(&mut authenticated, &public_key);
+        self.sign_arbitrary_data(&mut authenticated, &public_key);
+        self.concurrent_instance(&authenticated, key.as_deref());
+        self.shutdown(authenticated.instance);
+    }
+
+    fn list_keys(
+        &mut self,
+        instance: &mut Instance,
+        select_mode: SelectMode,
+    ) -> Option<ListSelectableKeysResponse> {
+        const NAME: &str = "list-selectable-keys";
+        let req = Request::ListSelectableKeys(ListSelectableKeysRequest {
+            v: ProtocolVersion::V1,
+        });
+        match instance.request::<ListSelectableKeysResponse, ListSelectableKeysError>(
+            &req,
+            self.options.timeout,
+        ) {
+            Ok(Ok(listed)) => {
+                self.record(
+                    NAME,
+                    Outcome::Pass(Some(format!(
+                        "{} key(s){}",
+                        listed.keys.len(),
+                        if listed.exhaustive {
+                            ""
+                        } else {
+                            ", not exhaustive"
+                        }
+                    ))),
+                );
+                Some(listed)
+            }
+            Ok(Err(ListSelectableKeysError::Unsupported)) => {
+                self.record(NAME, Outcome::Pass(Some("unsupported".into())));
+                None
+            }
+            Ok(Err(e)) if select_mode == SelectMode::Unsupported => {
+                self.record(NAME, Outcome::Pass(Some(format!("error: {e}"))));
+                None
+            }
+            Ok(Err(e)) => {
+                self.fail(NAME, e.to_string());
+                None
+            }
+            Err(e) => {
+                self.fail(NAME, e);
+                None
+            }
+        }
+    }
+
+    fn select_key(&self, instance: &mut Instance, key: &str) -> Result<(), String> {
+        let req = Request::KeySelect(KeySelectRequest {
+            v: ProtocolVersion::V1,
+            key: key.into(),
+        });
+        match instance.request::<KeySelectResponse, KeySelectError>(&req, self.options.timeout)? {
+            Ok(KeySelectResponse {}) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // With an exhaustive list, a name that isn't on it can't be a valid key.
+    fn reject_unlisted_key(&mut self, listed: &ListSelectableKeysResponse) {
+        const NAME: &str = "select-key (unlisted key)";
+        let mut unlisted = String::from("conformance-unlisted-key");
+        while listed.keys.contains(&unlisted) {
+            unlisted.push('_');
+        }
+        let mut instance = match self.launch() {
+            Ok((instance, _)) => instance,
+            Err(e) => return self.fail(NAME, e),
+        };
+        let req = Request::KeySelect(KeySelectRequest {
+            v: ProtocolVersion::V1,
+            key: unlisted.into(),
+        });
+        match instance.request::<KeySelectResponse, KeySelectError>(&req, self.options.timeout) {
+            Ok(Err(KeySelectError::InvalidKey { .. } | KeySelectError::Custom { .. })) => {
+                self.pass(NAME)
+            }
+            Ok(Err(KeySelectError::Unsupported)) => {
+                self.fail(NAME, "plugin listed keys but says selection is unsupported")
+            }
+            Ok(Ok(_)) => self.fail(
+                NAME,
+                "plugin accepted a key missing from its exhaustive list",
+            ),
+            Err(e) => self.fail(NAME, e),
+        }
+    }
+
+    fn describe_authn_mode(
+        &self,
+        instance: &mut Instance,
+    ) -> Result<DescribeAuthnModeResponse, String> {
+        let req = Request::DescribeAuthnMode(DescribeAuthnModeRequest {
+            v: ProtocolVersion::V1,
+        });
+        let resp = instance
+            .request::<DescribeAuthnModeResponse, DescribeAuthnModeError>(
+                &req,
+                self.options.timeout,
+            )?
+            .map_err(|e| e.to_string())?;
+        if matches!(resp.mode, AuthnMode::Url | AuthnMode::Message) && resp.value.is_none() {
+            return Err(format!(
+                "mode {} requires a value",
+                authn_mode_name(resp.mode)
+            ));
+        }
+        Ok(resp)
+    }
+
+    fn public_key_before_authn(&mut self, instance: &mut Instance) -> Option<Vec<u8>> {
+        const NAME: &str = "get-public-key (before authn)";
+        let req = Request::GetPublicKey(GetPublicKeyRequest {
+            v: ProtocolVersion::V1,
+        });
+        match instance
+            .request::<GetPublicKeyResponse, GetPublicKeyError>(&req, self.options.timeout)
+        {
+            Ok(Ok(resp)) => {
+                self.record(NAME, Outcome::Pass(Some("provided".into())));
+                Some(resp.public_key_der.into_owned())
+            }
+            Ok(Err(GetPublicKeyError::RequiresAuthn)) => {
+                self.record(NAME, Outcome::Pass(Some("requires authentication".into())));
+                None
+            }
+            Ok(Err(e)) => {
+                self.fail(NAME, e.to_string());
+                None
+            }
+            Err(e) => {
+                self.fail(NAME, e);
+                None
+            }
+        }
+    }
+
+    fn authenticate(&mut self, instance: &mut Instance) -> Result<(), String> {
+        let mode = match self.describe_authn_mode(instance) {
+            Ok(resp) => {
+                let note = match &resp.value {
+                    Some(value) => format!("{} ({value})", authn_mode_name(resp.mode)),
+                    None => authn_mode_name(resp.mode).into(),
+                };
+                self.record("describe-authn-mode", Outcome::Pass(Some(note)));
+                resp.mode
+            }
+            Err(e) => {
+                self.fail("describe-authn-mode", e.clone());
+                return Err(format!("could not learn the authentication mode: {e}"));
+            }
+        };
+        let (integrated, value) = self.integrated(mode);
+        let result = self.send_authenticate(instance, integrated, value)?;
+        let result = match result {
+            // SPEC.md: a plugin that reported `automatic` may have become stale, and can insist
+            // on doing it its own way.
+            Err(AuthenticateError::BadMode) if integrated == Some(AuthnMode::Automatic) => {
+                self.send_authenticate(instance, None, None)?
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => {
+                self.record(
+                    "authenticate",
+                    Outcome::Pass(Some(match integrated {
+                        Some(mode) => format!("integrated {}", authn_mode_name(mode)),
+                        None => "performed by the plugin".into(),
+                    })),
+                );
+                Ok(())
+            }
+            Err(AuthenticateError::BadMode) if integrated.is_none() => {
+                Err("bad-mode returned without an integrated mode".into())
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    // Hosts can only stand in for the plugin's own prompt when they know the password.
+    fn integrated(&self, mode: AuthnMode) -> (Option<AuthnMode>, Option<&str>) {
+        match (mode, &self.options.password) {
+            (AuthnMode::Automatic, _) => (Some(AuthnMode::Automatic), None),
+            (AuthnMode::Password, Some(password)) => (Some(AuthnMode::Password), Some(password)),
+            _ => (None, None),
+        }
+    }
+
+    fn send_authenticate(
+        &self,
+        instance: &mut Instance,
+        integrated: Option<AuthnMode>,
+        value: Option<&str>,
+    ) -> Result<Result<(), AuthenticateError>, String> {
+        let req = Request::Authenticate(AuthenticateRequest {
+            v: ProtocolVersion::V1,
+            integrated,
+            value: value.map(Into::into),
+        });
+        let resp = instance
+            .request::<AuthenticateResponse, AuthenticateError>(&req, self.options.authn_timeout)?;
+        Ok(resp.map(|AuthenticateResponse {}| ()))
+    }
+
+    fn public_key(
+        &mut self,
+        mut instance: Instance,
+        pre_authn_key: Option<Vec<u8>>,
+    ) -> Option<Authenticated> {
+        const NAME: &str = "get-public-key";
+        let req = Request::GetPublicKey(GetPublicKeyRequest {
+            v: ProtocolVersion::V1,
+        });
+        let public_key_der = match instance
+            .request::<GetPublicKeyResponse, GetPublicKeyError>(&req, self.options.timeout)
+        {
+            Ok(Ok(resp)) => resp.public_key_der.into_owned(),
+            Ok(Err(e)) => {
+                self.fail(NAME, e.to_string());
+                return None;
+            }
+            Err(e) => {
+                self.fail(NAME, e);
+                return None;
+            }
+        };
+        // SPEC.md: v1 plugins must only send keys that can be blindly converted to
+        // self-authenticating principals, i.e. DER-encoded SubjectPublicKeyInfo.
+        let public_key = match PublicKey::from_der(&public_key_der) {
+            Ok(public_key) => public_key,
+            Err(e) => {
+                self.fail(NAME, e);
+                return None;
+            }
+        };
+        if pre_authn_key.is_some_and(|pre| pre != public_key_der) {
+            self.fail(NAME, "public key changed after authentication");
+            return None;
+        }
+        let principal = Principal::self_authenticating(&public_key_der);
+        self.record(
+            NAME,
+            Outcome::Pass(Some(format!(
+                "{} key, principal {principal}",
+                public_key.algorithm()
+            ))),
+        );
+        Some(Authenticated {
+            instance,
+            public_key_der,
+            principal,
+        })
+    }
+
+    fn sign_envelopes(&mut self, authn: &mut Authenticated, public_key: &PublicKey) {
+        const NAME: &str = "sign-envelopes";
+        let canister_id = Principal::from_text(CANISTER).unwrap();
+        let ingress_expiry = now() + 4 * 60 * 1_000_000_000;
+        let call = EnvelopeContent::Call {
+            nonce: Some(rand::random::<[u8; 8]>().to_vec()),
+            ingress_expiry,
+            sender: authn.principal,
+            canister_id,
+            method_name: "conformance_update".into(),
+            arg: b"DIDL\0\0".to_vec(),
+        };
+        let read_state = EnvelopeContent::ReadState {
+            ingress_expiry,
+            sender: authn.principal,
+            paths: vec![vec![
+                Label::from("request_status"),
+                Label::from(call.to_request_id().to_vec()),
+            ]],
+        };
+        let query = EnvelopeContent::Query {
+            ingress_expiry,
+            sender: authn.principal,
+            canister_id,
+            method_name: "conformance_query".into(),
+            arg: b"DIDL\0\0".to_vec(),
+            nonce: None,
+        };
+        let contents = [call, read_state, query];
+        let req = Request::SignEnvelopes(SignEnvelopesRequest {
+            v: ProtocolVersion::V1,
+            contents: contents[..].into(),
+        });
+        let signatures = match authn
+            .instance
+            .request::<SignEnvelopesResponse, SignEnvelopesError>(&req, self.options.timeout)
+        {
+            Ok(Ok(resp)) => resp.signatures,
+            Ok(Err(e)) => return self.fail(NAME, e.to_string()),
+            Err(e) => return self.fail(NAME, e),
+        };
+        if signatures.len() != contents.len() {
+            return self.fail(
+                NAME,
+                format!(
+                    "{} signatures for {} envelopes",
+                    signatures.len(),
+                    contents.len()
+                ),
+            );
+        }
+        let checked = contents.iter().zip(signatures.iter()).enumerate().map(
+            |(pos, (content, signature))| {
+                public_key
+                    .verify(&content.to_request_id().signable(), signature)
+                    .map(|result| result.map_err(|e| format!("signature {pos}: {e}")))
+            },
+        );
+        self.record_verification(NAME, public_key, checked.collect());
+    }
+
+    fn sign_delegation(&mut self, authn: &mut Authenticated, public_key: &PublicKey) {
+        let mut session_key = ED25519_DER_PREFIX.to_vec();
+        session_key.extend_from_slice(&rand::random::<[u8; 32]>());
+        let desired_expiry = now() + 10 * 60 * 1_000_000_000;
+        let canisters = [Principal::from_text(CANISTER).unwrap()];
+        match self.delegate(authn, &session_key, desired_expiry, None, public_key) {
+            Ok(outcome) => self.record("sign-delegation", outcome),
+            Err(e) => self.fail("sign-delegation", e),
+        }
+        match self.delegate(
+            authn,
+            &session_key,
+            desired_expiry,
+            Some(&canisters),
+            public_key,
+        ) {
+            Ok(outcome) => self.record("sign-delegation (scoped)", outcome),
+            Err(e) => self.fail("sign-delegation (scoped)", e),
+        }
+    }
+
+    fn delegate(
+        &self,
+        authn: &mut Authenticated,
+        session_key: &[u8],
+        desired_expiry: u64,
+        canisters: Option<&[Principal]>,
+        public_key: &PublicKey,
+    ) -> Result<Outcome, String> {
+        let req = Request::SignDelegation(SignDelegationRequest {
+            v: ProtocolVersion::V1,
+            public_key_der: session_key.into(),
+            desired_expiry: desired_expiry.into(),
+            desired_canisters: canisters.map(Into::into),
+        });
+        let resp = match authn
+            .instance
+            .request::<SignDelegationResponse, SignDelegationError>(&req, self.options.timeout)?
+        {
+            Ok(resp) => resp,
+            Err(SignDelegationError::Unsupported) => {
+                return Ok(Outcome::Skip("plugin does not support delegations".into()));
+            }
+            Err(SignDelegationError::NeedsCanisterScoping) if canisters.is_none() => {
+                return Ok(Outcome::Pass(Some("requires canister scoping".into())));
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        // SPEC.md: the plugin may shorten the expiry, but not extend it.
+        if resp.expiry > u128::from(desired_expiry) {
+            return Err(format!(
+                "expiry {} is later than the desired {desired_expiry}",
+                resp.expiry
+            ));
+        }
+        if resp.expiry <= u128::from(now()) {
+            return Err(format!("expiry {} has already passed", resp.expiry));
+        }
+        let delegation = Delegation {
+            pubkey: session_key.to_vec(),
+            expiration: resp.expiry as u64,
+            targets: canisters.map(<[_]>::to_vec),
+        };
+        Ok(
+            match public_key.verify(&delegation.signable(), &resp.signature) {
+                Some(Ok(())) => Outcome::Pass(None),
+                Some(Err(e)) => Outcome::Fail(format!("signature does not verify: {e}")),
+                None => Outcome::Skip(format!(
+                    "cannot verify signatures of {}",
+                    public_key.algorithm()
+                )),
+            },
+        )
+    }
+
+    fn sign_arbitrary_data(&mut self, authn: &mut Authenticated, public_key: &PublicKey) {
+        const NAME: &str = "sign-arbitrary-data";
+        let data = b"ic-auth-plugin conformance test".to_vec();
+        let req = Request::SignArbitraryData(SignArbitraryDataRequest {
+            v: ProtocolVersion::V1,
+            data: data.as_slice().into(),
+        });
+        let signature = match authn
+            .instance
+            .request::<SignArbitraryDataResponse, SignArbitraryDataError>(
+                &req,
+                self.options.timeout,
+            ) {
+            Ok(Ok(resp)) => resp.signature,
+            Ok(Err(SignArbitraryDataError::Unsupported)) => {
+                return self.skip(NAME, "plugin does not support signing arbitrary data");
+            }
+            Ok(Err(e)) => return self.fail(NAME, e.to_string()),
+            Err(e) => return self.fail(NAME, e),
+        };
+        let checked = public_key.verify(&data, &signature);
+        self.record_verification(NAME, public_key, vec![checked]);
+    }
+
+    fn record_verification(
+        &mut self,
+        name: &'static str,
+        public_key: &PublicKey,
+        checked: Vec<Option<Result<(), String>>>,
+    ) {
+        if checked.iter().any(Option::is_none) {
+            return self.skip(
+                name,
+                format!("cannot verify signatures of {}", public_key.algorithm()),
+            );
+        }
+        match checked.into_iter().flatten().find_map(Result::err) {
+            Some(e) => self.fail(name, format!("signature does not verify: {e}")),
+            None => self.pass(name),
+        }
+    }
+
+    // SPEC.md: plugins must support several instances at once, e.g. to use several keys.
+    fn concurrent_instance(&mut self, authn: &Authenticated, key: Option<&str>) {
+        const NAME: &str = "concurrent instances";
+        let result: Result<(), String> = (|| {
+            let (mut instance, _) = self.launch()?;
+            if let Some(key) = key {
+                self.select_key(&mut instance, key)?;
+            }
+            let mode = self.describe_authn_mode(&mut instance)?.mode;
+            let (integrated, value) = self.integrated(mode);
+            self.send_authenticate(&mut instance, integrated, value)?
+                .map_err(|e| e.to_string())?;
+            let req = Request::GetPublicKey(GetPublicKeyRequest {
+                v: ProtocolVersion::V1,
+            });
+            let public_key = instance
+                .request::<GetPublicKeyResponse, GetPublicKeyError>(&req, self.options.timeout)?
+                .map_err(|e| e.to_string())?;
+            if public_key.public_key_der != authn.public_key_der {
+                return Err("second instance reported a different public key".into());
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => self.pass(NAME),
+            Err(e) => self.fail(NAME, e),
+        }
+    }
+
+    // SPEC.md: closing stdin is the signal to shut down gracefully.
+    fn shutdown(&mut self, mut instance: Instance) {
+        const NAME: &str = "shutdown on stdin close";
+        instance.close_stdin();
+        match instance.wait(self.options.timeout) {
+            Some(status) if status.success() => self.pass(NAME),
+            Some(status) => self.fail(NAME, format!("plugin exited with {status}")),
+            None => self.fail(
+                NAME,
+                format!(
+                    "plugin still running after {}s",
+                    self.options.timeout.as_secs()
+                ),
+            ),
+        }
+    }
+
+    // SPEC.md: plugins may abort on ill-formed messages or out-of-order requests, or reject them
+    // with an error response, but must not act on them. They must not exit with a zero status,
+    // which hosts read as expired authentication.
+    fn expect_rejection(&mut self, name: &'static str, line: &str) {
+        let mut instance = match self.launch() {
+            Ok((instance, _)) => instance,
+            Err(e) => return self.fail(name, e),
+        };
+        match instance.exchange(line, self.options.timeout) {
+            Reply::Line(resp) => match serde_json::from_str::<Result<Value, Value>>(&resp) {
+                Ok(Err(_)) => self.record(name, Outcome::Pass(Some("error response".into()))),
+                Ok(Ok(_)) => self.fail(name, format!("plugin accepted the request: {resp}")),
+                Err(e) => self.fail(name, format!("malformed response {resp:?}: {e}")),
+            },
+            Reply::Exited(Some(status)) if !status.success() => {
+                self.record(name, Outcome::Pass(Some(format!("aborted ({status})"))))
+            }
+            Reply::Exited(status) => self.fail(
+                name,
+                format!(
+                    "plugin exited ({}), which hosts read as expired authentication",
+                    describe(status)
+                ),
+            ),
+            Reply::TimedOut => self.fail(name, "plugin neither responded nor exited"),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn mode_name(mode: SelectMode) -> &'static str {
+    match mode {
+        SelectMode::Required => "required",
+        SelectMode::Supported => "supported",
+        SelectMode::Unsupported => "unsupported",
+    }
+}
+
+fn authn_mode_name(mode: AuthnMode) -> &'static str {
+    match mode {
+        AuthnMode::Password => "password",
+        AuthnMode::Url => "url",
+        AuthnMode::Message => "message",
+        AuthnMode::Window => "window",
+        AuthnMode::Automatic => "automatic",
+    }
+}
@@ -0,0 +1,52 @@
+use std::process::ExitCode;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use ic_auth_plugin_conformance::{Options, run};
+use pico_args::Arguments;
+
+const HELP: &str = "\
+Checks an IC auth plugin against the specification.
+
+Usage: ic-auth-plugin-conformance [OPTIONS] <PLUGIN>
+
+Options:
+    --key <NAME>            Key to select, if the plugin supports selection [default: first listed]
+    --password <PASSWORD>   Password or PIN to pass if the plugin asks for one
+    --timeout <SECS>        Timeout for each request [default: 10]
+    --authn-timeout <SECS>  Timeout for authentication [default: 120]";
+
+fn main() -> Result<ExitCode> {
+    let mut args = Arguments::from_env();
+    if args.contains(["-h", "--help"]) {
+        println!("{HELP}");
+        return Ok(ExitCode::SUCCESS);
+    }
+    let key = args.opt_value_from_str("--key")?;
+    let password = args.opt_value_from_str("--password")?;
+    let timeout: Option<u64> = args.opt_value_from_str("--timeout")?;
+    let authn_timeout: Option<u64> = args.opt_value_from_str("--authn-timeout")?;
+    let Some(program) = args.opt_free_from_os_str(|s| Ok::<_, anyhow::Error>(s.to_owned()))? else {
+        bail!("no plugin given\n\n{HELP}");
+    };
+    let rest = args.finish();
+    if !rest.is_empty() {
+        bail!("unexpected arguments {rest:?}");
+    }
+    let mut options = Options::new(program);
+    options.key = key;
+    options.password = password;
+    if let Some(timeout) = timeout {
+        options.timeout = Duration::from_secs(timeout);
+    }
+    if let Some(authn_timeout) = authn_timeout {
+        options.authn_timeout = Duration::from_secs(authn_timeout);
+    }
+    let report = run(&options);
+    println!("{report}");
+    Ok(if report.passed() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
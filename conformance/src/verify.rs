@@ -0,0 +1,81 @@
+use ed25519_consensus::VerificationKey;
+use p256::ecdsa::signature::Verifier;
+use spki::{ObjectIdentifier, SubjectPublicKeyInfoRef};
+
+const ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const SECP256R1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+const SECP256K1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.132.0.10");
+
+// The signature schemes of the IC interface specification that the harness can check locally. Keys
+// of other schemes (e.g. canister signatures) are still valid, but their signatures go unverified.
+pub(crate) enum PublicKey {
+    Ed25519(VerificationKey),
+    P256(p256::ecdsa::VerifyingKey),
+    Secp256k1(k256::ecdsa::VerifyingKey),
+    Other(ObjectIdentifier),
+}
+
+impl PublicKey {
+    pub(crate) fn from_der(der: &[u8]) -> Result<Self, String> {
+        let info = SubjectPublicKeyInfoRef::try_from(der)
+            .map_err(|e| format!("not a DER SubjectPublicKeyInfo: {e}"))?;
+        let bytes = info
+            .subject_public_key
+            .as_bytes()
+            .ok_or("public key has unused bits")?;
+        let oid = info.algorithm.oid;
+        let key = if oid == ED25519 {
+            let key = VerificationKey::try_from(bytes)
+                .map_err(|e| format!("invalid Ed25519 key: {e}"))?;
+            Self::Ed25519(key)
+        } else if oid == EC_PUBLIC_KEY {
+            let curve = info
+                .algorithm
+                .parameters_oid()
+                .map_err(|e| format!("EC key without a named curve: {e}"))?;
+            if curve == SECP256R1 {
+                let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(bytes)
+                    .map_err(|e| format!("invalid P-256 key: {e}"))?;
+                Self::P256(key)
+            } else if curve == SECP256K1 {
+                let key = k256::ecdsa::VerifyingKey::from_sec1_bytes(bytes)
+                    .map_err(|e| format!("invalid secp256k1 key: {e}"))?;
+                Self::Secp256k1(key)
+            } else {
+                Self::Other(curve)
+            }
+        } else {
+            Self::Other(oid)
+        };
+        Ok(key)
+    }
+
+    pub(crate) fn algorithm(&self) -> String {
+        match self {
+            Self::Ed25519(_) => "Ed25519".into(),
+            Self::P256(_) => "ECDSA P-256".into(),
+            Self::Secp256k1(_) => "ECDSA secp256k1".into(),
+            Self::Other(oid) => format!("algorithm {oid}"),
+        }
+    }
+
+    // `None` if the harness cannot verify signatures of this key's scheme.
+    pub(crate) fn verify(&self, message: &[u8], signature: &[u8]) -> Option<Result<(), String>> {
+        let result = match self {
+            Self::Ed25519(key) => ed25519_consensus::Signature::try_from(signature)
+                .and_then(|sig| key.verify(&sig, message))
+                .map_err(|e| e.to_string()),
+            // The IC signs the SHA-256 hash of the message, which is also what `Verifier` does.
+            Self::P256(key) => p256::ecdsa::Signature::from_slice(signature)
+                .and_then(|sig| key.verify(message, &sig))
+                .map_err(|e| e.to_string()),
+            // k256 only accepts low-S signatures, which the IC does not require.
+            Self::Secp256k1(key) => k256::ecdsa::Signature::from_slice(signature)
+                .and_then(|sig| key.verify(message, &sig.normalize_s().unwrap_or(sig)))
+                .map_err(|e| e.to_string()),
+            Self::Other(_) => return None,
+        };
+        Some(result)
+    }
+}
@@ -1,9 +1,19 @@
-use std::{env::current_exe, str::FromStr};
+use std::{
+    env::current_exe,
+    io::{self, BufRead, Write},
+    str::FromStr,
+};
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail};
+use ic_agent::export::Principal;
 use pico_args::Arguments;
 
-use crate::{config, config_path};
+use crate::{
+    config, config_path,
+    configure::configure,
+    pin,
+    token::{KeyName, Module, decode_hex, encode_hex},
+};
 
 pub fn run_cli() -> Result<()> {
     let mut args = Arguments::from_env();
@@ -13,30 +23,234 @@ pub fn run_cli() -> Result<()> {
             Info::ConfigPath => println!("{}", super::config_path().display()),
             Info::ActiveModule => println!("{}", super::config()?.pkcs11_module_path.display()),
         }
-    } else {
-        println!("An IC auth plugin for PKCS#11 hardware keys.");
-        if config().is_err() {
-            println!(
-                "\nMust be configured before first use. Edit {}.",
-                config_path().display()
-            );
+        return Ok(());
+    }
+    let command: Option<String> = args.opt_free_from_str()?;
+    match command.as_deref() {
+        Some("configure") => {
+            finish(args)?;
+            configure()
+        }
+        Some("tokens") => {
+            finish(args)?;
+            list_tokens()
+        }
+        Some("keys") => {
+            let token = args.opt_free_from_str()?;
+            finish(args)?;
+            list_keys(token)
+        }
+        Some("public-key") => {
+            let key = args.free_from_str().context("missing KEY")?;
+            finish(args)?;
+            public_key(key)
+        }
+        Some("generate") => {
+            let label = args.value_from_str("--label")?;
+            let id = args.value_from_str("--id")?;
+            let token = args.free_from_str().context("missing TOKEN")?;
+            finish(args)?;
+            generate(token, label, id)
         }
-        let self_path = current_exe().unwrap();
-        let self_name = self_path.file_name().unwrap();
+        Some("delete") => {
+            let yes = args.contains("--yes");
+            let key = args.free_from_str().context("missing KEY")?;
+            finish(args)?;
+            delete(key, yes)
+        }
+        Some(command) => bail!("unknown command {command}"),
+        None => {
+            help();
+            Ok(())
+        }
+    }
+}
+
+fn help() {
+    println!("An IC auth plugin for PKCS#11 hardware keys.");
+    if config().is_err() {
         println!(
-            "\nPlugins do not need to be run directly. To use the plugin with an app that supports plugins, \
-            go to the app's plugin settings and enter the path {}",
-            self_path.display()
+            "\nMust be configured before first use. Run `{} configure`, or edit {}.",
+            current_exe()
+                .unwrap()
+                .file_name()
+                .unwrap()
+                .to_string_lossy(),
+            config_path().display()
         );
-        println!(
-            "
+    }
+    let self_path = current_exe().unwrap();
+    let self_name = self_path.file_name().unwrap();
+    println!(
+        "\nPlugins do not need to be run directly. To use the plugin with an app that supports plugins, \
+        go to the app's plugin settings and enter the path {}",
+        self_path.display()
+    );
+    println!(
+        "
+{0} configure
+    Finds PKCS#11 modules and tokens, and writes the configuration for the chosen key.
 {0} --print config-path
     Displays the path to the configuration.
 {0} --print active-module
-    Displays the PKCS#11 module currently configured.",
-            self_name.to_string_lossy()
+    Displays the PKCS#11 module currently configured.
+{0} tokens
+    Lists the tokens the module can see, by slot index.
+{0} keys [TOKEN]
+    Lists the prime256v1 keys on a token (by slot index or label), or on every token.
+{0} public-key KEY
+    Displays the DER public key and principal of a key.
+{0} generate TOKEN --label LABEL --id HEX
+    Generates a prime256v1 key on a token (by slot index or label).
+{0} delete KEY [--yes]
+    Deletes a key from its token, asking for confirmation unless --yes is given.
+
+Keys are named `token-label/key-label`, or `slot:key-id` with the slot index and hex key ID.",
+        self_name.to_string_lossy()
+    );
+}
+
+fn finish(args: Arguments) -> Result<()> {
+    let rest = args.finish();
+    if !rest.is_empty() {
+        bail!("unexpected arguments {rest:?}");
+    }
+    Ok(())
+}
+
+fn module() -> Result<Module> {
+    let path = config()?.pkcs11_module_path;
+    Module::load(&path).with_context(|| format!("failed to load {}", path.display()))
+}
+
+// Finds a token by slot index, or else by label.
+pub fn token_index(module: &Module, token: &str) -> Result<usize> {
+    let tokens = module.tokens()?;
+    if let Ok(index) = token.parse::<usize>() {
+        if index < tokens.len() {
+            return Ok(index);
+        }
+        bail!(
+            "no slot {index} (found {} slots with a token)",
+            tokens.len()
+        );
+    }
+    let mut matching = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, info)| info.label == token);
+    match (matching.next(), matching.next()) {
+        (Some((index, _)), None) => Ok(index),
+        (None, _) => bail!("no token labelled {token:?}"),
+        _ => bail!("several tokens are labelled {token:?}, use the slot index instead"),
+    }
+}
+
+// Asks for the token's PIN on the terminal, if it has one.
+pub fn token_pin(module: &Module, index: usize) -> Result<Option<String>> {
+    if !module.login_required(index)? {
+        return Ok(None);
+    }
+    // The wizard runs before there is a config, so the PIN is asked for on the terminal until then.
+    let program = config().ok().and_then(|config| config.pinentry_program);
+    let description = format!("Enter the PIN for slot {index}");
+    match pin::read_pin(program.as_deref(), &description, None)? {
+        Some(pin) => Ok(Some(pin)),
+        None => bail!("no PIN entered"),
+    }
+}
+
+fn list_tokens() -> Result<()> {
+    let tokens = module()?.tokens()?;
+    if tokens.is_empty() {
+        println!("No tokens found.");
+    }
+    for (index, token) in tokens.iter().enumerate() {
+        println!("{index}: {}", token.label);
+        println!("    slot: {}", token.slot_description);
+        println!(
+            "    device: {} {}, serial {}",
+            token.manufacturer, token.model, token.serial
+        );
+        if token.login_required {
+            println!("    requires a PIN");
+        }
+    }
+    Ok(())
+}
+
+fn list_keys(token: Option<String>) -> Result<()> {
+    let module = module()?;
+    let indices = match token {
+        Some(token) => vec![token_index(&module, &token)?],
+        None => (0..module.slots()?.len()).collect(),
+    };
+    for index in indices {
+        let pin = token_pin(&module, index)?;
+        let keys = module.keys(index, pin.as_deref())?;
+        println!("{index}: {}", module.token_label(module.slots()?[index])?);
+        if keys.is_empty() {
+            println!("    no prime256v1 keys");
+        }
+        for key in keys {
+            let halves = match (key.public, key.private) {
+                (true, true) => "",
+                (true, false) => " (public key only)",
+                (false, _) => " (no public key, cannot be used)",
+            };
+            println!(
+                "    {index}:{} {:?}{halves}",
+                encode_hex(&key.id),
+                key.label
+            );
+        }
+    }
+    Ok(())
+}
+
+fn public_key(key: String) -> Result<()> {
+    let name: KeyName = key.parse().map_err(anyhow::Error::msg)?;
+    let module = module()?;
+    let location = module.locate(&name)?;
+    let public_key = module.public_key(&location)?;
+    println!("principal: {}", Principal::self_authenticating(&public_key));
+    println!("public-key-der: {}", encode_hex(&public_key));
+    Ok(())
+}
+
+fn generate(token: String, label: String, id: String) -> Result<()> {
+    let Some(id) = decode_hex(&id) else {
+        bail!("key ID {id:?} is not hex");
+    };
+    let module = module()?;
+    let index = token_index(&module, &token)?;
+    let pin = token_pin(&module, index)?;
+    let public_key = module.generate(index, &label, &id, pin.as_deref())?;
+    println!("Generated key {index}:{}.", encode_hex(&id));
+    println!("principal: {}", Principal::self_authenticating(&public_key));
+    println!("public-key-der: {}", encode_hex(&public_key));
+    Ok(())
+}
+
+fn delete(key: String, yes: bool) -> Result<()> {
+    let name: KeyName = key.parse().map_err(anyhow::Error::msg)?;
+    let module = module()?;
+    let location = module.locate(&name)?;
+    if !yes {
+        print!(
+            "Delete every key with ID {} in slot {}? This cannot be undone. [y/N] ",
+            location.key_id, location.slot
         );
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("not deleted");
+        }
     }
+    let pin = token_pin(&module, location.slot)?;
+    let deleted = module.delete(&location, pin.as_deref())?;
+    println!("Deleted {deleted} key objects.");
     Ok(())
 }
 
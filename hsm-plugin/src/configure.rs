@@ -0,0 +1,278 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use ic_agent::export::Principal;
+
+use crate::{
+    Config, DEFAULT_CONFIG,
+    cli::{token_index, token_pin},
+    config_path,
+    token::{KeyName, Module},
+};
+
+// Where PKCS#11 modules are commonly installed, by OpenSC (which covers most smartcards and the
+// Nitrokey HSM), SoftHSM, and device vendors' own packages.
+#[cfg(target_os = "linux")]
+const MODULE_DIRS: &[&str] = &[
+    "/usr/lib",
+    "/usr/lib64",
+    "/usr/local/lib",
+    "/usr/lib/x86_64-linux-gnu",
+    "/usr/lib/aarch64-linux-gnu",
+    "/usr/lib/pkcs11",
+    "/usr/lib64/pkcs11",
+    "/usr/lib/x86_64-linux-gnu/pkcs11",
+    "/usr/lib/aarch64-linux-gnu/pkcs11",
+    "/usr/lib/softhsm",
+    "/usr/lib64/softhsm",
+    "/usr/local/lib/softhsm",
+    "/usr/lib/x86_64-linux-gnu/softhsm",
+    "/usr/lib/aarch64-linux-gnu/softhsm",
+];
+#[cfg(target_os = "linux")]
+const MODULE_NAMES: &[&str] = &[
+    "opensc-pkcs11.so",
+    "libsofthsm2.so",
+    "libykcs11.so",
+    "libeTPkcs11.so",
+    "libsc-hsm-pkcs11.so",
+];
+
+#[cfg(target_os = "macos")]
+const MODULE_DIRS: &[&str] = &[
+    "/Library/OpenSC/lib",
+    "/opt/homebrew/lib",
+    "/opt/homebrew/lib/softhsm",
+    "/usr/local/lib",
+    "/usr/local/lib/softhsm",
+];
+#[cfg(target_os = "macos")]
+const MODULE_NAMES: &[&str] = &[
+    "opensc-pkcs11.so",
+    "libsofthsm2.so",
+    "libykcs11.dylib",
+    "libeTPkcs11.dylib",
+];
+
+#[cfg(windows)]
+const MODULE_DIRS: &[&str] = &[
+    r"C:\Windows\System32",
+    r"C:\Program Files\OpenSC Project\OpenSC\pkcs11",
+    r"C:\Program Files\Yubico\Yubico PIV Tool\bin",
+    r"C:\SoftHSM2\lib",
+];
+#[cfg(windows)]
+const MODULE_NAMES: &[&str] = &[
+    "opensc-pkcs11.dll",
+    "libykcs11.dll",
+    "softhsm2-x64.dll",
+    "eTPKCS11.dll",
+];
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const MODULE_DIRS: &[&str] = &["/usr/lib", "/usr/local/lib"];
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const MODULE_NAMES: &[&str] = &["opensc-pkcs11.so", "libsofthsm2.so"];
+
+// Walks the user through picking a module, token and key, and writes the result as the config.
+pub fn configure() -> Result<()> {
+    let path = config_path();
+    if path.exists() && fs::read_to_string(&path)? != DEFAULT_CONFIG {
+        let answer = ask(&format!(
+            "{} already exists and will be replaced. Continue? [y/N] ",
+            path.display()
+        ))?;
+        if !matches!(&*answer, "y" | "Y" | "yes") {
+            bail!("configuration left unchanged");
+        }
+    }
+
+    println!("Searching for PKCS#11 modules...");
+    let mut modules = Vec::new();
+    for candidate in candidates() {
+        match probe(&candidate) {
+            Ok(tokens) => {
+                println!("{}: {}", modules.len() + 1, candidate.display());
+                match &*tokens {
+                    [] => println!("    no tokens"),
+                    tokens => {
+                        for token in tokens {
+                            println!("    token {token:?}");
+                        }
+                    }
+                }
+                modules.push(candidate);
+            }
+            Err(e) => println!("   (skipping {}: {e:#})", candidate.display()),
+        }
+    }
+    if modules.is_empty() {
+        println!("No modules found. Your device's manufacturer, or OpenSC, should provide one.");
+    }
+    let module_path = loop {
+        let answer = ask("Module number, or the path to another module: ")?;
+        let path = match answer.parse::<usize>() {
+            Ok(n) if (1..=modules.len()).contains(&n) => modules[n - 1].clone(),
+            Ok(_) => {
+                println!("There is no module {answer}.");
+                continue;
+            }
+            Err(_) => PathBuf::from(answer),
+        };
+        match probe(&path) {
+            Ok(_) => break path,
+            Err(e) => println!("{}: {e:#}", path.display()),
+        }
+    };
+
+    let module = Module::load(&module_path)?;
+    let tokens = module.tokens()?;
+    if tokens.is_empty() {
+        bail!("the module sees no tokens, insert your device and run `configure` again");
+    }
+    for (index, token) in tokens.iter().enumerate() {
+        println!(
+            "{index}: {:?} ({} {})",
+            token.label, token.manufacturer, token.model
+        );
+    }
+    let index = match tokens.len() {
+        1 => 0,
+        _ => loop {
+            match token_index(&module, &ask("Token (slot index or label): ")?) {
+                Ok(index) => break index,
+                Err(e) => println!("{e}"),
+            }
+        },
+    };
+
+    let pin = token_pin(&module, index)?;
+    let token = &tokens[index].label;
+    let mut keys = Vec::new();
+    // Keys without both halves cannot be used by the plugin.
+    for key in module.keys(index, pin.as_deref())? {
+        if !(key.public && key.private) {
+            continue;
+        }
+        let ids = KeyName::Ids {
+            slot: index,
+            key: key.id.clone(),
+        };
+        let location = module.locate(&ids)?;
+        // Labels make a more readable name, as long as they lead back to the same key.
+        let labels = KeyName::Labels {
+            token: token.clone(),
+            key: key.label.clone(),
+        };
+        let name = match module.locate(&labels) {
+            Ok(other) if other == location && labels.to_string().parse() == Ok(labels.clone()) => {
+                labels
+            }
+            _ => ids,
+        };
+        let public_key = module.public_key(&location)?;
+        keys.push((name, Principal::self_authenticating(&public_key)));
+    }
+    let default_key = if keys.is_empty() {
+        println!(
+            "The token has no usable prime256v1 keys. Create one with the `generate` command, then \
+            set `default-key` in the config or let the app select it."
+        );
+        None
+    } else {
+        for (n, (name, principal)) in keys.iter().enumerate() {
+            println!("{}: {name} ({principal})", n + 1);
+        }
+        loop {
+            let answer = ask("Key number to use by default, or nothing to let the app choose: ")?;
+            if answer.is_empty() {
+                break None;
+            }
+            match answer.parse::<usize>() {
+                Ok(n) if (1..=keys.len()).contains(&n) => break Some(keys[n - 1].0.clone()),
+                _ => println!("There is no key {answer}."),
+            }
+        }
+    };
+    drop(module);
+    // The config has no setting for the token alone, so without a default key the plugin falls back
+    // to slot 0 whenever the app does not select a key.
+    if default_key.is_none() && index != 0 {
+        println!(
+            "Warning: unless the app selects a key, the plugin looks for key 0:01 on slot 0, not on \
+            {token:?}. Set `default-key` in the config to use this token by default."
+        );
+    }
+
+    let contents = render(&module_path, default_key.as_ref());
+    let _: Config = toml::from_str(&contents).context("generated an invalid config")?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, contents)?;
+    println!("Wrote {}.", path.display());
+    Ok(())
+}
+
+// Module files that exist in the usual places, without duplicates from symlinks.
+fn candidates() -> Vec<PathBuf> {
+    let mut seen = BTreeSet::new();
+    let mut candidates = Vec::new();
+    for dir in MODULE_DIRS {
+        for name in MODULE_NAMES {
+            let path = Path::new(dir).join(name);
+            if let Ok(canonical) = path.canonicalize() {
+                if seen.insert(canonical) {
+                    candidates.push(path);
+                }
+            }
+        }
+    }
+    candidates
+}
+
+// Loads a module, which fails unless it exports `C_GetFunctionList`, and returns the labels of its
+// tokens.
+fn probe(path: &Path) -> Result<Vec<String>> {
+    if !path.is_file() {
+        bail!("not a file");
+    }
+    let module = Module::load(path).context("not a usable PKCS#11 module")?;
+    let mut labels = Vec::new();
+    for slot in module.slots()? {
+        labels.push(module.token_label(slot)?);
+    }
+    Ok(labels)
+}
+
+// The default config with the chosen settings filled in, so its comments still document the rest.
+fn render(module_path: &Path, default_key: Option<&KeyName>) -> String {
+    let quote = |s: String| toml::Value::String(s).to_string();
+    let mut contents = DEFAULT_CONFIG.replace(
+        "# pkcs11-module-path = \"\"",
+        &format!(
+            "pkcs11-module-path = {}",
+            quote(module_path.to_string_lossy().into_owned())
+        ),
+    );
+    if let Some(key) = default_key {
+        contents = contents.replace(
+            "# default-key = \"\"",
+            &format!("default-key = {}", quote(key.to_string())),
+        );
+    }
+    contents
+}
+
+fn ask(prompt: &str) -> Result<String> {
+    print!("{prompt}");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer)? == 0 {
+        bail!("no answer given");
+    }
+    Ok(answer.trim().to_owned())
+}
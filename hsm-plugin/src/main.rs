@@ -1,33 +1,39 @@
-use std::{
-    borrow::Cow,
-    io::{BufRead, Write, stdin, stdout},
-    path::PathBuf,
-};
+use std::{borrow::Cow, path::PathBuf};
 
-use anyhow::{Context, Result, bail};
+use anyhow::{Result, bail};
 use cli::run_cli;
 use directories::ProjectDirs;
 use ic_agent::{Identity, identity::Delegation};
+use ic_auth_plugin_server::{AuthPlugin, abort, invoked_as_plugin, run};
 use ic_auth_plugin_types::{
-    AuthenticateError, AuthenticateResponse, AuthenticateResult, AuthnMode,
-    DescribeAuthnModeResponse, DescribeAuthnModeResult, GetPublicKeyError, GetPublicKeyResponse,
-    GetPublicKeyResult, Greeting, KeySelectError, KeySelectResult, ListSelectableKeysError,
-    ListSelectableKeysResult, Request, SelectMode, SignArbitraryDataError,
-    SignArbitraryDataResponse, SignArbitraryDataResult, SignDelegationError,
-    SignDelegationResponse, SignDelegationResult, SignEnvelopesError, SignEnvelopesResponse,
-    SignEnvelopesResult,
+    AuthenticateError, AuthenticateRequest, AuthenticateResponse, AuthenticateResult, AuthnMode,
+    DescribeAuthnModeError, DescribeAuthnModeRequest, DescribeAuthnModeResponse,
+    DescribeAuthnModeResult, GetPublicKeyError, GetPublicKeyRequest, GetPublicKeyResponse,
+    GetPublicKeyResult, KeySelectError, KeySelectRequest, KeySelectResponse, KeySelectResult,
+    ListSelectableKeysError, ListSelectableKeysRequest, ListSelectableKeysResponse,
+    ListSelectableKeysResult, SelectMode, SignArbitraryDataError, SignArbitraryDataRequest,
+    SignArbitraryDataResponse, SignArbitraryDataResult, SignDelegationError, SignDelegationRequest,
+    SignDelegationResponse, SignDelegationResult, SignEnvelopesError, SignEnvelopesRequest,
+    SignEnvelopesResponse, SignEnvelopesResult,
 };
 use ic_identity_hsm::{HardwareIdentity, HardwareIdentityError};
-use pkcs11::types::{CKR_PIN_INCORRECT, CKR_PIN_INVALID};
+use pkcs11::{
+    errors::Error as Pkcs11Error,
+    types::{CKR_PIN_INCORRECT, CKR_PIN_INVALID},
+};
 use serde::Deserialize;
+use token::{KeyLocation, KeyName, Module};
 
 mod cli;
+mod configure;
+mod pin;
+mod token;
+
+// Tokens lock after a few wrong PINs, so the plugin gives the user fewer tries than that.
+const PIN_ATTEMPTS: usize = 3;
 
 fn main() -> Result<()> {
-    if std::env::args()
-        .nth(1)
-        .is_some_and(|arg| arg == "--ic-auth-plugin")
-    {
+    if invoked_as_plugin() {
         auth_loop()?;
     } else {
         run_cli()?;
@@ -35,196 +41,319 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn auth_loop() -> Result</* ! */ ()> {
-    let mut stdin = stdin().lock();
-    let mut stdout = stdout().lock();
-    let config = match config() {
-        Ok(config) => config,
-        Err(err) => {
-            writeln!(
-                stdout,
-                "{}",
-                serde_json::to_string(&Greeting {
-                    v: vec![1],
-                    select: None,
-                    abort: Some(format!("{err}"))
-                })?
-            )?;
-            std::process::abort();
+fn auth_loop() -> Result<()> {
+    let config = config().unwrap_or_else(|err| abort(err));
+    run(HsmPlugin {
+        config,
+        module: None,
+        keys: None,
+        location: None,
+        zero_auth_attempt: None,
+        ident: None,
+    })?;
+    Ok(())
+}
+
+struct HsmPlugin {
+    config: Config,
+    // Loaded on first use, and released before a `HardwareIdentity` loads the module itself. The keys
+    // listed last are kept, since the module cannot be loaded again while that identity lives.
+    module: Option<Module>,
+    keys: Option<(Vec<KeyName>, bool)>,
+    location: Option<KeyLocation>,
+    // `None` until the key is known; then whether the key could be opened without a PIN.
+    zero_auth_attempt: Option<Option<HardwareIdentity>>,
+    ident: Option<HardwareIdentity>,
+}
+
+impl HsmPlugin {
+    fn ident(&self) -> &HardwareIdentity {
+        self.ident
+            .as_ref()
+            .expect("signing requests are only sent after authentication")
+    }
+
+    fn module(&mut self) -> Result<&Module, Pkcs11Error> {
+        if self.module.is_none() {
+            self.module = Some(Module::load(&self.config.pkcs11_module_path)?);
+        }
+        Ok(self.module.as_ref().unwrap())
+    }
+
+    fn list_keys(&mut self) -> Result<(Vec<KeyName>, bool), Pkcs11Error> {
+        let keys = self.module()?.list_keys()?;
+        self.keys = Some(keys.clone());
+        Ok(keys)
+    }
+
+    // Called before creating a `HardwareIdentity`. The keys are listed first if they never were, so
+    // that `list-selectable-keys` can still be answered afterwards.
+    fn release_module(&mut self) {
+        if self.module.is_some() && self.keys.is_none() {
+            let _ = self.list_keys();
+        }
+        self.module = None;
+    }
+
+    // The selected key, or the configured default if the host did not select one.
+    fn location(&mut self) -> Result<KeyLocation, String> {
+        if let Some(location) = &self.location {
+            return Ok(location.clone());
         }
-    };
-    writeln!(
-        stdout,
-        "{}",
-        serde_json::to_string(&Greeting {
-            v: vec![1],
-            select: Some(SelectMode::Unsupported),
-            abort: None,
-        })?
-    )?;
-    let mut msg_buf = String::new();
-    let zero_auth_attempt =
-        match HardwareIdentity::new(&config.pkcs11_module_path, 0, "01", || Err(String::new())) {
-            Ok(ident) => Some(ident),
-            Err(HardwareIdentityError::UserPinRequired(_)) => None,
-            Err(e) => return Err(e.into()),
+        let location = match self.config.default_key.clone() {
+            Some(name) => self
+                .module()
+                .map_err(|e| e.to_string())?
+                .locate(&name)
+                .map_err(|e| format!("default key {name}: {e}"))?,
+            None => KeyLocation {
+                slot: 0,
+                key_id: "01".into(),
+            },
         };
-    let ident = loop {
-        msg_buf.clear();
-        stdin.read_line(&mut msg_buf)?;
-        let req = serde_json::from_str::<Request>(&msg_buf)?;
-        match req {
-            Request::ListSelectableKeys(_) => writeln!(
-                stdout,
-                "{}",
-                serde_json::to_string(&ListSelectableKeysResult::Err(
-                    ListSelectableKeysError::Unsupported
-                ))?
-            )?,
-            Request::KeySelect(_) => writeln!(
-                stdout,
-                "{}",
-                serde_json::to_string(&KeySelectResult::Err(KeySelectError::Unsupported))?
-            )?,
-            Request::GetPublicKey(_) => writeln!(
-                stdout,
-                "{}",
-                serde_json::to_string(&GetPublicKeyResult::Err(GetPublicKeyError::RequiresAuthn))?
-            )?,
-            Request::DescribeAuthnMode(_) => writeln!(
-                stdout,
-                "{}",
-                serde_json::to_string(&DescribeAuthnModeResult::Ok(DescribeAuthnModeResponse {
-                    mode: if zero_auth_attempt.is_some() {
-                        AuthnMode::Automatic
-                    } else {
-                        AuthnMode::Password
-                    },
-                    value: None
-                }))?
-            )?,
-            Request::Authenticate(auth) => {
-                if let Some(ident) = zero_auth_attempt {
-                    writeln!(
-                        stdout,
-                        "{}",
-                        serde_json::to_string(&AuthenticateResult::Ok(AuthenticateResponse {}))?
-                    )?;
-                    break ident;
-                }
-                match auth.integrated {
-                    Some(mode) => {
-                        if mode != AuthnMode::Password {
-                            writeln!(
-                                stdout,
-                                "{}",
-                                serde_json::to_string(&AuthenticateResult::Err(
-                                    AuthenticateError::BadMode
-                                ))?
-                            )?;
-                        } else {
-                            let password = auth
-                                .value
-                                .context("integrated password missing")?
-                                .into_owned();
-                            match HardwareIdentity::new(&config.pkcs11_module_path, 0, "01", || {
-                                Ok(password)
-                            }) {
-                                Ok(ident) => {
-                                    writeln!(
-                                        stdout,
-                                        "{}",
-                                        serde_json::to_string(&AuthenticateResult::Ok(
-                                            AuthenticateResponse {}
-                                        ))?
-                                    )?;
-                                    break ident;
-                                }
-                                Err(HardwareIdentityError::PKCS11(
-                                    pkcs11::errors::Error::Pkcs11(
-                                        CKR_PIN_INCORRECT | CKR_PIN_INVALID,
-                                    ),
-                                )) => writeln!(
-                                    stdout,
-                                    "{}",
-                                    serde_json::to_string(&AuthenticateResult::Err(
-                                        AuthenticateError::BadAuthn {
-                                            message: "Incorrect PIN".into()
-                                        }
-                                    ))?
-                                )?,
-                                Err(e) => writeln!(
-                                    stdout,
-                                    "{}",
-                                    serde_json::to_string(&AuthenticateResult::Err(
-                                        AuthenticateError::Custom {
-                                            message: format!("{e}")
-                                        }
-                                    ))?
-                                )?,
-                            }
-                        }
-                    }
-                    None => todo!(),
-                }
+        self.location = Some(location.clone());
+        Ok(location)
+    }
+
+    // Opens the key with `pin`. Fails with `None` if the PIN was incorrect.
+    fn login(&mut self, location: &KeyLocation, pin: String) -> Result<(), Option<String>> {
+        self.release_module();
+        match HardwareIdentity::new(
+            &self.config.pkcs11_module_path,
+            location.slot,
+            &location.key_id,
+            || Ok(pin),
+        ) {
+            Ok(ident) => {
+                self.ident = Some(ident);
+                Ok(())
             }
-            _ => bail!("handshake process violated"),
+            Err(HardwareIdentityError::PKCS11(pkcs11::errors::Error::Pkcs11(
+                CKR_PIN_INCORRECT | CKR_PIN_INVALID,
+            ))) => Err(None),
+            Err(e) => Err(Some(e.to_string())),
         }
-    };
-    loop {
-        msg_buf.clear();
-        stdin.read_line(&mut msg_buf)?;
-        let req = serde_json::from_str::<Request>(&msg_buf)?;
-        match req {
-            Request::GetPublicKey(_) => writeln!(
-                stdout,
-                "{}",
-                serde_json::to_string(&GetPublicKeyResult::Ok(GetPublicKeyResponse {
-                    public_key_der: ident.public_key().unwrap().into()
-                }))?
-            )?,
-            Request::SignEnvelopes(req) => {
-                let signatures: Result<Vec<_>, _> = req
-                    .contents
-                    .iter()
-                    .map(|envelope| {
-                        ident
-                            .sign(envelope)
-                            .map(|sig| Cow::from(sig.signature.unwrap()))
-                    })
-                    .collect();
-                let res: SignEnvelopesResult = match signatures {
-                    Ok(signatures) => Ok(SignEnvelopesResponse {
-                        signatures: signatures.into(),
-                    }),
-                    Err(err) => Err(SignEnvelopesError::Custom { message: err }),
-                };
-                writeln!(stdout, "{}", serde_json::to_string(&res)?)?;
+    }
+
+    // Asks the user for the PIN directly, since SPEC.md requires plugins to be able to authenticate
+    // without the host's help.
+    fn login_interactively(&mut self, location: &KeyLocation) -> AuthenticateResult {
+        let description = format!("Enter the PIN for {}", location.describe());
+        let mut error = None;
+        for _ in 0..PIN_ATTEMPTS {
+            let pin = pin::read_pin(self.config.pinentry_program.as_deref(), &description, error)
+                .map_err(|e| AuthenticateError::Custom {
+                message: format!("{e:#}"),
+            })?;
+            let Some(pin) = pin else {
+                return Err(AuthenticateError::BadAuthn {
+                    message: "PIN entry was cancelled".into(),
+                });
+            };
+            match self.login(location, pin) {
+                Ok(()) => return Ok(AuthenticateResponse {}),
+                Err(None) => error = Some("Incorrect PIN"),
+                Err(Some(message)) => return Err(AuthenticateError::Custom { message }),
             }
-            Request::SignArbitraryData(req) => {
-                let res: SignArbitraryDataResult = match ident.sign_arbitrary(&req.data) {
-                    Ok(signature) => Ok(SignArbitraryDataResponse {
-                        signature: signature.signature.unwrap().into(),
-                    }),
-                    Err(err) => Err(SignArbitraryDataError::Custom { message: err }),
-                };
-                writeln!(stdout, "{}", serde_json::to_string(&res)?)?;
+        }
+        Err(AuthenticateError::BadAuthn {
+            message: "Incorrect PIN".into(),
+        })
+    }
+
+    // How the PIN is entered when the key needs one: by the host, or by us in a pinentry window or on
+    // the terminal.
+    fn pin_mode(&self) -> AuthnMode {
+        match (self.config.pin_entry, &self.config.pinentry_program) {
+            (PinEntry::Host, _) => AuthnMode::Password,
+            (PinEntry::Pinentry, Some(_)) => AuthnMode::Window,
+            (PinEntry::Pinentry | PinEntry::Terminal, _) => AuthnMode::Message,
+        }
+    }
+
+    // Tries the key without a PIN, which succeeds on tokens that do not require logging in.
+    fn zero_auth_attempt(&mut self) -> Result<&mut Option<HardwareIdentity>, String> {
+        if self.zero_auth_attempt.is_none() {
+            let location = self.location()?;
+            self.release_module();
+            let attempt = match HardwareIdentity::new(
+                &self.config.pkcs11_module_path,
+                location.slot,
+                &location.key_id,
+                || Err(String::new()),
+            ) {
+                Ok(ident) => Some(ident),
+                Err(HardwareIdentityError::UserPinRequired(_)) => None,
+                Err(e) => return Err(e.to_string()),
+            };
+            self.zero_auth_attempt = Some(attempt);
+        }
+        Ok(self.zero_auth_attempt.as_mut().unwrap())
+    }
+}
+
+impl AuthPlugin for HsmPlugin {
+    fn select_mode(&self) -> SelectMode {
+        SelectMode::Supported
+    }
+
+    fn list_selectable_keys(
+        &mut self,
+        _req: ListSelectableKeysRequest,
+    ) -> ListSelectableKeysResult {
+        let has_identity = self.ident.is_some() || matches!(self.zero_auth_attempt, Some(Some(_)));
+        let listed = match &self.keys {
+            Some(keys) if has_identity => Ok(keys.clone()),
+            None if has_identity => {
+                Err("the keys could not be listed before authentication".into())
             }
-            Request::SignDelegation(req) => {
-                let res: SignDelegationResult = match ident.sign_delegation(&Delegation {
-                    expiration: req.desired_expiry.try_into().unwrap(),
-                    targets: req.desired_canisters.map(Cow::into_owned),
-                    pubkey: req.public_key_der.into_owned(),
-                }) {
-                    Ok(signature) => Ok(SignDelegationResponse {
-                        expiry: req.desired_expiry,
-                        signature: signature.signature.unwrap().into(),
-                    }),
-                    Err(err) => Err(SignDelegationError::Custom { message: err }),
+            _ => self.list_keys().map_err(|e| e.to_string()),
+        };
+        let (names, exhaustive) = listed.map_err(|e| ListSelectableKeysError::Custom {
+            message: format!("failed to enumerate keys: {e}"),
+        })?;
+        Ok(ListSelectableKeysResponse {
+            keys: names.iter().map(KeyName::to_string).collect(),
+            exhaustive,
+        })
+    }
+
+    fn select_key(&mut self, req: KeySelectRequest<'_>) -> KeySelectResult {
+        let name: KeyName = req
+            .key
+            .parse()
+            .map_err(|message| KeySelectError::InvalidKey {
+                message: Some(message),
+            })?;
+        let module = self.module().map_err(|e| KeySelectError::Custom {
+            message: format!("failed to load PKCS#11 module: {e}"),
+        })?;
+        match module.locate(&name) {
+            Ok(location) => {
+                self.location = Some(location);
+                Ok(KeySelectResponse {})
+            }
+            Err(e) if e.is_invalid_key() => Err(KeySelectError::InvalidKey {
+                message: Some(e.to_string()),
+            }),
+            Err(e) => Err(KeySelectError::Custom {
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    fn describe_authn_mode(&mut self, _req: DescribeAuthnModeRequest) -> DescribeAuthnModeResult {
+        let zero_auth_attempt = self
+            .zero_auth_attempt()
+            .map_err(|message| DescribeAuthnModeError::Custom { message })?;
+        if zero_auth_attempt.is_some() {
+            return Ok(DescribeAuthnModeResponse {
+                mode: AuthnMode::Automatic,
+                value: None,
+            });
+        }
+        let location = self
+            .location()
+            .map_err(|message| DescribeAuthnModeError::Custom { message })?;
+        let mode = self.pin_mode();
+        Ok(DescribeAuthnModeResponse {
+            mode,
+            value: (mode == AuthnMode::Message)
+                .then(|| format!("Enter the PIN for {} in the terminal", location.describe())),
+        })
+    }
+
+    fn authenticate(&mut self, req: AuthenticateRequest<'_>) -> AuthenticateResult {
+        let zero_auth_attempt = self
+            .zero_auth_attempt()
+            .map_err(|message| AuthenticateError::Custom { message })?;
+        if let Some(ident) = zero_auth_attempt.take() {
+            self.ident = Some(ident);
+            return Ok(AuthenticateResponse {});
+        }
+        let location = self
+            .location()
+            .map_err(|message| AuthenticateError::Custom { message })?;
+        let mode = self.pin_mode();
+        match req.integrated {
+            Some(AuthnMode::Password) if mode == AuthnMode::Password => {
+                let Some(password) = req.value else {
+                    return Err(AuthenticateError::Custom {
+                        message: "integrated password missing".into(),
+                    });
                 };
-                writeln!(stdout, "{}", serde_json::to_string(&res)?)?;
+                match self.login(&location, password.into_owned()) {
+                    Ok(()) => Ok(AuthenticateResponse {}),
+                    Err(None) => Err(AuthenticateError::BadAuthn {
+                        message: "Incorrect PIN".into(),
+                    }),
+                    Err(Some(message)) => Err(AuthenticateError::Custom { message }),
+                }
             }
-            _ => bail!("handshake messages repeated"),
+            // Showing our message or announcing our window is all a host can do for us.
+            Some(integrated) if integrated != mode || mode == AuthnMode::Password => {
+                Err(AuthenticateError::BadMode)
+            }
+            _ => self.login_interactively(&location),
+        }
+    }
+
+    fn get_public_key(&mut self, _req: GetPublicKeyRequest) -> GetPublicKeyResult<'_> {
+        match &self.ident {
+            Some(ident) => Ok(GetPublicKeyResponse {
+                public_key_der: ident.public_key().unwrap().into(),
+            }),
+            None => Err(GetPublicKeyError::RequiresAuthn),
+        }
+    }
+
+    fn sign_envelopes(&mut self, req: SignEnvelopesRequest<'_>) -> SignEnvelopesResult<'_> {
+        let ident = self.ident();
+        let signatures: Result<Vec<_>, _> = req
+            .contents
+            .iter()
+            .map(|envelope| {
+                ident
+                    .sign(envelope)
+                    .map(|sig| Cow::from(sig.signature.unwrap()))
+            })
+            .collect();
+        match signatures {
+            Ok(signatures) => Ok(SignEnvelopesResponse {
+                signatures: signatures.into(),
+            }),
+            Err(err) => Err(SignEnvelopesError::Custom { message: err }),
+        }
+    }
+
+    fn sign_arbitrary_data(
+        &mut self,
+        req: SignArbitraryDataRequest<'_>,
+    ) -> SignArbitraryDataResult<'_> {
+        match self.ident().sign_arbitrary(&req.data) {
+            Ok(signature) => Ok(SignArbitraryDataResponse {
+                signature: signature.signature.unwrap().into(),
+            }),
+            Err(err) => Err(SignArbitraryDataError::Custom { message: err }),
+        }
+    }
+
+    fn sign_delegation(&mut self, req: SignDelegationRequest<'_>) -> SignDelegationResult<'_> {
+        let Ok(expiration) = req.desired_expiry.try_into() else {
+            return Err(SignDelegationError::Custom {
+                message: format!("expiry {} is out of range", req.desired_expiry),
+            });
+        };
+        match self.ident().sign_delegation(&Delegation {
+            expiration,
+            targets: req.desired_canisters.map(Cow::into_owned),
+            pubkey: req.public_key_der.into_owned(),
+        }) {
+            Ok(signature) => Ok(SignDelegationResponse {
+                expiry: req.desired_expiry,
+                signature: signature.signature.unwrap().into(),
+            }),
+            Err(err) => Err(SignDelegationError::Custom { message: err }),
         }
     }
 }
@@ -236,9 +365,10 @@ fn config_path() -> PathBuf {
         .join("config.toml")
 }
 
+const DEFAULT_CONFIG: &str =
+    include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/default-config.toml"));
+
 fn config() -> Result<Config> {
-    const DEFAULT_CONFIG: &str =
-        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/default-config.toml"));
     let path = config_path();
     if path.exists() {
         let contents = std::fs::read_to_string(&path)?;
@@ -251,7 +381,7 @@ fn config() -> Result<Config> {
         std::fs::write(&path, DEFAULT_CONFIG)?;
     }
     bail!(
-        "pkcs11-ic-auth-plugin has not been configured, please edit {}",
+        "pkcs11-ic-auth-plugin has not been configured, please run it with `configure` or edit {}",
         path.display()
     );
 }
@@ -260,4 +390,18 @@ fn config() -> Result<Config> {
 #[serde(rename_all = "kebab-case")]
 struct Config {
     pkcs11_module_path: PathBuf,
+    default_key: Option<KeyName>,
+    #[serde(default)]
+    pin_entry: PinEntry,
+    pinentry_program: Option<PathBuf>,
+}
+
+// Who asks for the PIN. Unless it is `Host`, the PIN is never given to the app.
+#[derive(Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum PinEntry {
+    #[default]
+    Host,
+    Pinentry,
+    Terminal,
 }
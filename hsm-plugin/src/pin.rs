@@ -0,0 +1,139 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, bail};
+
+// Asks the user for a PIN without involving the host, through `pinentry` if configured and through
+// the controlling terminal otherwise. Returns `None` if the user cancelled. `error` describes why a
+// previous attempt failed.
+pub fn read_pin(
+    pinentry: Option<&Path>,
+    description: &str,
+    error: Option<&str>,
+) -> Result<Option<String>> {
+    match pinentry {
+        Some(program) => pinentry_pin(program, description, error),
+        None => {
+            let prompt = match error {
+                Some(error) => format!("{error}. {description}: "),
+                None => format!("{description}: "),
+            };
+            // rpassword reads from /dev/tty, not stdin, which carries the protocol.
+            match rpassword::prompt_password(prompt) {
+                Ok(pin) if pin.is_empty() => Ok(None),
+                Ok(pin) => Ok(Some(pin)),
+                Err(e) => Err(e).context("no terminal to ask for the PIN on"),
+            }
+        }
+    }
+}
+
+// Speaks just enough of the Assuan protocol to have a pinentry program (as used by GnuPG) show a
+// PIN dialog.
+fn pinentry_pin(program: &Path, description: &str, error: Option<&str>) -> Result<Option<String>> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("failed to start {}", program.display()))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut command = |line: &str| -> Result<Option<String>> {
+        if !line.is_empty() {
+            writeln!(stdin, "{line}")?;
+            stdin.flush()?;
+        }
+        let mut data = None;
+        loop {
+            let mut resp = String::new();
+            if stdout.read_line(&mut resp)? == 0 {
+                bail!("pinentry exited unexpectedly");
+            }
+            let resp = resp.trim_end_matches(['\r', '\n']);
+            if resp == "OK" || resp.starts_with("OK ") {
+                return Ok(Some(data.unwrap_or_default()));
+            } else if let Some(d) = resp.strip_prefix("D ") {
+                data.get_or_insert_with(String::new).push_str(&unescape(d));
+            } else if resp.starts_with("ERR") {
+                return Ok(None);
+            }
+            // Status and comment lines are ignored.
+        }
+    };
+    let result = (|| {
+        if command("")?.is_none() {
+            bail!("pinentry refused the connection");
+        }
+        command("SETTITLE IC auth plugin")?;
+        command(&format!("SETDESC {}", escape(description)))?;
+        command("SETPROMPT PIN:")?;
+        if let Some(error) = error {
+            command(&format!("SETERROR {}", escape(error)))?;
+        }
+        command("GETPIN")
+    })();
+    let _ = command("BYE");
+    let _ = child.wait();
+    Ok(result?.filter(|pin| !pin.is_empty()))
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' | '\r' | '\n' => escaped.push_str(&format!("%{:02X}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let decoded = (b == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_percent_and_line_breaks() {
+        assert_eq!(escape("100%\r\nok"), "100%25%0D%0Aok");
+        assert_eq!(escape("plain text: é"), "plain text: é");
+    }
+
+    #[test]
+    fn unescapes_percent_sequences() {
+        assert_eq!(unescape("100%25%0d%0Aok"), "100%\r\nok");
+        assert_eq!(unescape("%C3%A9"), "é");
+        // Invalid sequences are kept as they are.
+        for s in ["%", "%2", "%zz", "%+1", "50% off"] {
+            assert_eq!(unescape(s), s);
+        }
+        let pin = "a%b\r\nc%%0A";
+        assert_eq!(unescape(&escape(pin)), pin);
+    }
+}
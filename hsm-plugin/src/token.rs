@@ -0,0 +1,673 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use pkcs11::{
+    Ctx,
+    errors::Error as Pkcs11Error,
+    types::{
+        CK_ATTRIBUTE, CK_ATTRIBUTE_TYPE, CK_BBOOL, CK_FLAGS, CK_KEY_TYPE, CK_MECHANISM,
+        CK_OBJECT_CLASS, CK_OBJECT_HANDLE, CK_SESSION_HANDLE, CK_SLOT_ID, CK_TRUE, CK_ULONG,
+        CK_UNAVAILABLE_INFORMATION, CKA_CLASS, CKA_EC_PARAMS, CKA_EC_POINT, CKA_ID, CKA_KEY_TYPE,
+        CKA_LABEL, CKA_PRIVATE, CKA_SENSITIVE, CKA_SIGN, CKA_TOKEN, CKA_VERIFY, CKF_LOGIN_REQUIRED,
+        CKF_RW_SESSION, CKF_SERIAL_SESSION, CKF_TOKEN_INITIALIZED, CKK_EC, CKM_EC_KEY_PAIR_GEN,
+        CKO_PRIVATE_KEY, CKO_PUBLIC_KEY, CKR_OK, CKR_USER_ALREADY_LOGGED_IN, CKU_USER,
+    },
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+// The DER encoding of the prime256v1 curve OID, as found in CKA_EC_PARAMS. This is the only curve
+// `HardwareIdentity` signs with.
+const PRIME256V1: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+// The DER SubjectPublicKeyInfo header for an uncompressed prime256v1 point, which follows it.
+const PRIME256V1_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+// A key as named by the host or the config: `slot-label/key-label` picks the token by its label and
+// the key by its label, `slot:keyid` picks the token by its index among the slots with a token
+// present and the key by its hex-encoded CKA_ID.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum KeyName {
+    Labels { token: String, key: String },
+    Ids { slot: usize, key: Vec<u8> },
+}
+
+impl FromStr for KeyName {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((token, key)) = s.split_once('/') {
+            if token.is_empty() || key.is_empty() {
+                return Err(format!("{s:?} is missing a token or key label"));
+            }
+            Ok(Self::Labels {
+                token: token.into(),
+                key: key.into(),
+            })
+        } else if let Some((slot, key)) = s.split_once(':') {
+            let slot = slot
+                .parse()
+                .map_err(|_| format!("slot {slot:?} is not a slot index"))?;
+            let key = decode_hex(key).ok_or_else(|| format!("key ID {key:?} is not hex"))?;
+            Ok(Self::Ids { slot, key })
+        } else {
+            Err(format!(
+                "{s:?} should be either `token-label/key-label` or `slot:key-id`"
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for KeyName {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for KeyName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Labels { token, key } => write!(f, "{token}/{key}"),
+            Self::Ids { slot, key } => write!(f, "{slot}:{}", encode_hex(key)),
+        }
+    }
+}
+
+// The arguments `HardwareIdentity::new` takes to open a key.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct KeyLocation {
+    pub slot: usize,
+    pub key_id: String,
+}
+
+impl KeyLocation {
+    pub fn describe(&self) -> String {
+        format!("key {} in slot {}", self.key_id, self.slot)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LocateError {
+    #[error("no slot {index} (found {count} slots with a token)")]
+    NoSlot { index: usize, count: usize },
+    #[error("no token labelled {0:?}")]
+    NoToken(String),
+    #[error("several tokens are labelled {0:?}, select the key by slot index instead")]
+    AmbiguousToken(String),
+    #[error("no key {key} on {token}")]
+    NoKey { key: String, token: String },
+    #[error("several keys on {token} are labelled {key:?}, select the key by ID instead")]
+    AmbiguousKey { key: String, token: String },
+    #[error("PKCS#11 error: {0}")]
+    Pkcs11(#[from] Pkcs11Error),
+}
+
+impl LocateError {
+    // Whether the name itself was at fault, rather than the module or device.
+    pub fn is_invalid_key(&self) -> bool {
+        !matches!(self, Self::Pkcs11(_))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error(transparent)]
+    Locate(#[from] LocateError),
+    #[error("key {0} has no public key object to read")]
+    NoPublicKey(String),
+    #[error("the public key of key {0} is not an uncompressed prime256v1 point")]
+    MalformedPublicKey(String),
+    #[error("a key with ID {0} already exists on this token")]
+    KeyExists(String),
+    #[error("PKCS#11 error: {0}")]
+    Pkcs11(#[from] Pkcs11Error),
+}
+
+// A token as shown to the user, at its index among the slots with a token present.
+pub struct TokenInfo {
+    pub slot_description: String,
+    pub label: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub serial: String,
+    pub login_required: bool,
+}
+
+// A prime256v1 key, and which halves of it were visible.
+pub struct KeyInfo {
+    pub id: Vec<u8>,
+    pub label: String,
+    pub public: bool,
+    pub private: bool,
+}
+
+// A loaded PKCS#11 module. Only one can be initialized per process, so it must be dropped before a
+// `HardwareIdentity` is created (which loads its own).
+pub struct Module {
+    ctx: Ctx,
+}
+
+impl Module {
+    pub fn load(path: &Path) -> Result<Self, Pkcs11Error> {
+        Ok(Self {
+            ctx: Ctx::new_and_initialize(path)?,
+        })
+    }
+
+    pub fn slots(&self) -> Result<Vec<CK_SLOT_ID>, Pkcs11Error> {
+        self.ctx.get_slot_list(true)
+    }
+
+    pub fn token_label(&self, slot: CK_SLOT_ID) -> Result<String, Pkcs11Error> {
+        Ok(String::from(self.ctx.get_token_info(slot)?.label))
+    }
+
+    pub fn tokens(&self) -> Result<Vec<TokenInfo>, Pkcs11Error> {
+        let mut tokens = Vec::new();
+        for slot in self.slots()? {
+            let slot_info = self.ctx.get_slot_info(slot)?;
+            let info = self.ctx.get_token_info(slot)?;
+            tokens.push(TokenInfo {
+                slot_description: String::from(slot_info.slotDescription),
+                label: String::from(info.label),
+                manufacturer: String::from(info.manufacturerID),
+                model: String::from(info.model),
+                serial: String::from(info.serialNumber),
+                login_required: info.flags & CKF_LOGIN_REQUIRED != 0,
+            });
+        }
+        Ok(tokens)
+    }
+
+    // Whether the token at `index` needs a PIN before its private keys can be seen or changed.
+    pub fn login_required(&self, index: usize) -> Result<bool, LocateError> {
+        let info = self.ctx.get_token_info(self.slot(index)?)?;
+        Ok(info.flags & CKF_LOGIN_REQUIRED != 0)
+    }
+
+    // The prime256v1 keys on the token at `index`. Without `pin`, tokens that require logging in
+    // only show keys with a public key object.
+    pub fn keys(&self, index: usize, pin: Option<&str>) -> Result<Vec<KeyInfo>, LocateError> {
+        let session = self.open(self.slot(index)?, false, pin)?;
+        let keys = self
+            .ec_keys(session, CKO_PUBLIC_KEY)
+            .and_then(|public| Ok((public, self.ec_keys(session, CKO_PRIVATE_KEY)?)));
+        let _ = self.ctx.close_session(session);
+        let (public, private) = keys?;
+        let mut keys: Vec<KeyInfo> = public
+            .into_iter()
+            .map(|(id, label)| KeyInfo {
+                id,
+                label,
+                public: true,
+                private: false,
+            })
+            .collect();
+        for (id, label) in private {
+            match keys.iter_mut().find(|key| key.id == id && !key.private) {
+                Some(key) => key.private = true,
+                None => keys.push(KeyInfo {
+                    id,
+                    label,
+                    public: false,
+                    private: true,
+                }),
+            }
+        }
+        Ok(keys)
+    }
+
+    // The DER-encoded public key of a located key, as `HardwareIdentity` would report it. Reading
+    // it never needs a PIN, since public key objects are not private.
+    pub fn public_key(&self, location: &KeyLocation) -> Result<Vec<u8>, KeyError> {
+        let session = self.open(self.slot(location.slot)?, false, None)?;
+        let id = decode_hex(&location.key_id).unwrap_or_default();
+        let template = [
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&CKO_PUBLIC_KEY),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+        ];
+        let point = self.find(session, &template, 1).and_then(|objects| {
+            objects
+                .first()
+                .map(|&object| self.attribute(session, object, CKA_EC_POINT))
+                .transpose()
+        });
+        let _ = self.ctx.close_session(session);
+        let Some(point) = point? else {
+            return Err(KeyError::NoPublicKey(location.key_id.clone()));
+        };
+        spki(&point).ok_or_else(|| KeyError::MalformedPublicKey(location.key_id.clone()))
+    }
+
+    // Generates a prime256v1 key pair on the token at `index`, and returns its DER-encoded public
+    // key. The private key is marked sensitive, so it can never leave the token.
+    pub fn generate(
+        &self,
+        index: usize,
+        label: &str,
+        id: &[u8],
+        pin: Option<&str>,
+    ) -> Result<Vec<u8>, KeyError> {
+        let session = self.open(self.slot(index)?, true, pin)?;
+        let result = self.generate_in(session, label, id);
+        let _ = self.ctx.close_session(session);
+        result
+    }
+
+    fn generate_in(
+        &self,
+        session: CK_SESSION_HANDLE,
+        label: &str,
+        id: &[u8],
+    ) -> Result<Vec<u8>, KeyError> {
+        let filter = CK_ATTRIBUTE::new(CKA_ID).with_bytes(id);
+        if !self.key_ids(session, CKO_PUBLIC_KEY, filter)?.is_empty()
+            || !self.key_ids(session, CKO_PRIVATE_KEY, filter)?.is_empty()
+        {
+            return Err(KeyError::KeyExists(encode_hex(id)));
+        }
+        let yes: CK_BBOOL = CK_TRUE;
+        let mechanism = CK_MECHANISM {
+            mechanism: CKM_EC_KEY_PAIR_GEN,
+            pParameter: std::ptr::null_mut(),
+            ulParameterLen: 0,
+        };
+        let public_template = [
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&yes),
+            CK_ATTRIBUTE::new(CKA_VERIFY).with_bool(&yes),
+            CK_ATTRIBUTE::new(CKA_EC_PARAMS).with_bytes(PRIME256V1),
+            CK_ATTRIBUTE::new(CKA_LABEL).with_string(label),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+        ];
+        let private_template = [
+            CK_ATTRIBUTE::new(CKA_TOKEN).with_bool(&yes),
+            CK_ATTRIBUTE::new(CKA_PRIVATE).with_bool(&yes),
+            CK_ATTRIBUTE::new(CKA_SENSITIVE).with_bool(&yes),
+            CK_ATTRIBUTE::new(CKA_SIGN).with_bool(&yes),
+            CK_ATTRIBUTE::new(CKA_LABEL).with_string(label),
+            CK_ATTRIBUTE::new(CKA_ID).with_bytes(id),
+        ];
+        let (public, _) =
+            self.ctx
+                .generate_key_pair(session, &mechanism, &public_template, &private_template)?;
+        let point = self.attribute(session, public, CKA_EC_POINT)?;
+        spki(&point).ok_or_else(|| KeyError::MalformedPublicKey(encode_hex(id)))
+    }
+
+    // Destroys every key object with the located key's ID, and returns how many there were.
+    pub fn delete(&self, location: &KeyLocation, pin: Option<&str>) -> Result<usize, KeyError> {
+        let session = self.open(self.slot(location.slot)?, true, pin)?;
+        let id = decode_hex(&location.key_id).unwrap_or_default();
+        let result = (|| {
+            let mut deleted = 0;
+            for class in [CKO_PRIVATE_KEY, CKO_PUBLIC_KEY] {
+                let template = [
+                    CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+                    CK_ATTRIBUTE::new(CKA_ID).with_bytes(&id),
+                ];
+                for object in self.find(session, &template, 1024)? {
+                    self.ctx.destroy_object(session, object)?;
+                    deleted += 1;
+                }
+            }
+            Ok(deleted)
+        })();
+        let _ = self.ctx.close_session(session);
+        result
+    }
+
+    fn slot(&self, index: usize) -> Result<CK_SLOT_ID, LocateError> {
+        let slots = self.slots()?;
+        slots.get(index).copied().ok_or(LocateError::NoSlot {
+            index,
+            count: slots.len(),
+        })
+    }
+
+    // Opens a session, logged in if `pin` is given. Logins are shared by all sessions of the
+    // process, so an existing one is fine.
+    fn open(
+        &self,
+        slot: CK_SLOT_ID,
+        write: bool,
+        pin: Option<&str>,
+    ) -> Result<CK_SESSION_HANDLE, Pkcs11Error> {
+        let mut flags: CK_FLAGS = CKF_SERIAL_SESSION;
+        if write {
+            flags |= CKF_RW_SESSION;
+        }
+        let session = self.ctx.open_session(slot, flags, None, None)?;
+        if let Some(pin) = pin {
+            match self.ctx.login(session, CKU_USER, Some(pin)) {
+                Ok(()) | Err(Pkcs11Error::Pkcs11(CKR_USER_ALREADY_LOGGED_IN)) => {}
+                Err(e) => {
+                    let _ = self.ctx.close_session(session);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(session)
+    }
+
+    pub fn locate(&self, name: &KeyName) -> Result<KeyLocation, LocateError> {
+        let slots = self.slots()?;
+        let (index, filter) = match name {
+            KeyName::Labels { token, key } => {
+                let mut matching = Vec::new();
+                for (index, &slot) in slots.iter().enumerate() {
+                    if self.token_label(slot)? == *token {
+                        matching.push(index);
+                    }
+                }
+                match *matching {
+                    [index] => (index, CK_ATTRIBUTE::new(CKA_LABEL).with_string(key)),
+                    [] => return Err(LocateError::NoToken(token.clone())),
+                    _ => return Err(LocateError::AmbiguousToken(token.clone())),
+                }
+            }
+            KeyName::Ids { slot, key } if *slot < slots.len() => {
+                (*slot, CK_ATTRIBUTE::new(CKA_ID).with_bytes(key))
+            }
+            KeyName::Ids { slot, .. } => {
+                return Err(LocateError::NoSlot {
+                    index: *slot,
+                    count: slots.len(),
+                });
+            }
+        };
+        // Private keys are only visible after logging in, but tokens that require it usually label
+        // the matching public key the same.
+        let session = self.session(slots[index])?;
+        let ids = self
+            .key_ids(session, CKO_PUBLIC_KEY, filter)
+            .and_then(|ids| match *ids {
+                [] => self.key_ids(session, CKO_PRIVATE_KEY, filter),
+                _ => Ok(ids),
+            });
+        let _ = self.ctx.close_session(session);
+        let (key, token) = match name {
+            KeyName::Labels { token, key } => (format!("{key:?}"), format!("token {token:?}")),
+            KeyName::Ids { slot, key } => (encode_hex(key), format!("slot {slot}")),
+        };
+        match &*ids? {
+            [id] => Ok(KeyLocation {
+                slot: index,
+                key_id: encode_hex(id),
+            }),
+            [] => Err(LocateError::NoKey { key, token }),
+            // `HardwareIdentity` uses the first key with the ID, so duplicate IDs are not an error.
+            [id, ..] if matches!(name, KeyName::Ids { .. }) => Ok(KeyLocation {
+                slot: index,
+                key_id: encode_hex(id),
+            }),
+            _ => Err(LocateError::AmbiguousKey { key, token }),
+        }
+    }
+
+    fn key_ids(
+        &self,
+        session: CK_SESSION_HANDLE,
+        class: CK_OBJECT_CLASS,
+        filter: CK_ATTRIBUTE,
+    ) -> Result<Vec<Vec<u8>>, Pkcs11Error> {
+        let template = [CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class), filter];
+        self.find(session, &template, 2)?
+            .into_iter()
+            .filter_map(|object| self.optional_attribute(session, object, CKA_ID).transpose())
+            .collect()
+    }
+
+    // Names every prime256v1 key on every token, and whether that is all of them: tokens that require
+    // logging in hide their private keys until then, so for those only keys with a visible public key
+    // can be listed, and tokens that cannot be read at all are left out. Uninitialized tokens hold no
+    // keys. Keys are named by label where the labels identify them, and by ID otherwise.
+    pub fn list_keys(&self) -> Result<(Vec<KeyName>, bool), Pkcs11Error> {
+        let slots = self.slots()?;
+        let mut exhaustive = true;
+        // Indexed like `slots`, so that `KeyName::Ids` keeps counting every slot with a token. Labels
+        // of uninitialized tokens still count against uniqueness, since `locate` sees them too.
+        let mut tokens = Vec::new();
+        let mut labels = Vec::new();
+        for &slot in &slots {
+            match self.ctx.get_token_info(slot) {
+                Ok(info) => {
+                    let label = String::from(info.label);
+                    labels.push(label.clone());
+                    tokens.push(
+                        (info.flags & CKF_TOKEN_INITIALIZED != 0)
+                            .then_some((label, info.flags & CKF_LOGIN_REQUIRED != 0)),
+                    );
+                }
+                Err(_) => {
+                    exhaustive = false;
+                    tokens.push(None);
+                }
+            }
+        }
+        let mut names = Vec::new();
+        for (index, &slot) in slots.iter().enumerate() {
+            let Some((token, login_required)) = &tokens[index] else {
+                continue;
+            };
+            let Ok(session) = self.session(slot) else {
+                exhaustive = false;
+                continue;
+            };
+            let keys = self.ec_keys(session, CKO_PRIVATE_KEY).and_then(|mut keys| {
+                if *login_required {
+                    for key in self.ec_keys(session, CKO_PUBLIC_KEY)? {
+                        if !keys.iter().any(|(id, _)| *id == key.0) {
+                            keys.push(key);
+                        }
+                    }
+                }
+                Ok(keys)
+            });
+            let _ = self.ctx.close_session(session);
+            let Ok(keys) = keys else {
+                exhaustive = false;
+                continue;
+            };
+            exhaustive &= !login_required;
+            let token_unique = !token.is_empty()
+                && !token.contains('/')
+                && labels.iter().filter(|other| *other == token).count() == 1;
+            for (id, label) in &keys {
+                let label_unique = !label.is_empty()
+                    && keys.iter().filter(|(_, other)| other == label).count() == 1;
+                if token_unique && label_unique {
+                    names.push(KeyName::Labels {
+                        token: token.clone(),
+                        key: label.clone(),
+                    });
+                } else if !id.is_empty() {
+                    names.push(KeyName::Ids {
+                        slot: index,
+                        key: id.clone(),
+                    });
+                }
+            }
+        }
+        Ok((names, exhaustive))
+    }
+
+    // The IDs and labels of the prime256v1 keys of `class` visible in the session.
+    fn ec_keys(
+        &self,
+        session: CK_SESSION_HANDLE,
+        class: CK_OBJECT_CLASS,
+    ) -> Result<Vec<(Vec<u8>, String)>, Pkcs11Error> {
+        let key_type: CK_KEY_TYPE = CKK_EC;
+        let template = [
+            CK_ATTRIBUTE::new(CKA_CLASS).with_ck_ulong(&class),
+            CK_ATTRIBUTE::new(CKA_KEY_TYPE).with_ck_ulong(&key_type),
+        ];
+        let mut keys = Vec::new();
+        // Objects that hide their curve or ID cannot be used, so they are skipped rather than failing
+        // the whole listing.
+        for object in self.find(session, &template, 1024)? {
+            if self
+                .optional_attribute(session, object, CKA_EC_PARAMS)?
+                .as_deref()
+                != Some(PRIME256V1)
+            {
+                continue;
+            }
+            let Some(id) = self.optional_attribute(session, object, CKA_ID)? else {
+                continue;
+            };
+            let label = self
+                .optional_attribute(session, object, CKA_LABEL)?
+                .unwrap_or_default();
+            keys.push((id, String::from_utf8_lossy(&label).into_owned()));
+        }
+        Ok(keys)
+    }
+
+    fn find(
+        &self,
+        session: CK_SESSION_HANDLE,
+        template: &[CK_ATTRIBUTE],
+        max: CK_ULONG,
+    ) -> Result<Vec<CK_OBJECT_HANDLE>, Pkcs11Error> {
+        self.ctx.find_objects_init(session, template)?;
+        let found = self.ctx.find_objects(session, max);
+        self.ctx.find_objects_final(session)?;
+        found
+    }
+
+    pub fn session(&self, slot: CK_SLOT_ID) -> Result<CK_SESSION_HANDLE, Pkcs11Error> {
+        self.ctx.open_session(slot, CKF_SERIAL_SESSION, None, None)
+    }
+
+    pub fn attribute(
+        &self,
+        session: CK_SESSION_HANDLE,
+        object: CK_OBJECT_HANDLE,
+        attribute: CK_ATTRIBUTE_TYPE,
+    ) -> Result<Vec<u8>, Pkcs11Error> {
+        self.optional_attribute(session, object, attribute)?
+            .ok_or(Pkcs11Error::UnavailableInformation)
+    }
+
+    // Like `attribute`, but `None` if the object has no such attribute or will not reveal it.
+    // pkcs11 reports both as success, with the length set to CK_UNAVAILABLE_INFORMATION.
+    fn optional_attribute(
+        &self,
+        session: CK_SESSION_HANDLE,
+        object: CK_OBJECT_HANDLE,
+        attribute: CK_ATTRIBUTE_TYPE,
+    ) -> Result<Option<Vec<u8>>, Pkcs11Error> {
+        let mut template = vec![CK_ATTRIBUTE::new(attribute)];
+        let (rv, _) = self
+            .ctx
+            .get_attribute_value(session, object, &mut template)?;
+        if rv != CKR_OK || template[0].ulValueLen == CK_UNAVAILABLE_INFORMATION {
+            return Ok(None);
+        }
+        let value = vec![0; template[0].ulValueLen as usize];
+        let mut template = vec![CK_ATTRIBUTE::new(attribute).with_bytes(&value)];
+        let (rv, _) = self
+            .ctx
+            .get_attribute_value(session, object, &mut template)?;
+        if rv != CKR_OK {
+            return Err(Pkcs11Error::Pkcs11(rv));
+        }
+        Ok(Some(value))
+    }
+}
+
+// Wraps a CKA_EC_POINT in a SubjectPublicKeyInfo. The point should be a DER OCTET STRING, but some
+// modules return it bare.
+fn spki(point: &[u8]) -> Option<Vec<u8>> {
+    let point = match point {
+        [0x04, 0x41, rest @ ..] if rest.len() == 65 => rest,
+        _ if point.len() == 65 => point,
+        _ => return None,
+    };
+    if point[0] != 0x04 {
+        return None;
+    }
+    Some([PRIME256V1_SPKI_PREFIX, point].concat())
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` would also accept a sign.
+    if hex.is_empty() || hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The prime256v1 generator point, uncompressed.
+    const POINT: &str = "046b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c2964fe342e2fe1a7f9b8ee7eb4a7c0f9e162bce33576b315ececbb6406837bf51f5";
+
+    #[test]
+    fn key_names_round_trip() {
+        for name in ["My token/signing key", "0:01", "12:deadbeef"] {
+            assert_eq!(name.parse::<KeyName>().unwrap().to_string(), name);
+        }
+        assert_eq!(
+            "3:0A0b".parse(),
+            Ok(KeyName::Ids {
+                slot: 3,
+                key: vec![0x0a, 0x0b],
+            })
+        );
+        assert_eq!(
+            "token/a/b".parse(),
+            Ok(KeyName::Labels {
+                token: "token".into(),
+                key: "a/b".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_key_names() {
+        for name in [
+            "", "key", "/key", "token/", "x:01", "-1:01", "0:", "0:1", "0:0g", "0:+1",
+        ] {
+            assert!(name.parse::<KeyName>().is_err(), "{name:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00fF10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(encode_hex(&[0x00, 0xff, 0x10]), "00ff10");
+        for hex in ["", "0", "012", "zz", "+1", "-1", "0x", "é1"] {
+            assert_eq!(decode_hex(hex), None, "{hex:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn encodes_points_as_spki() {
+        let point = decode_hex(POINT).unwrap();
+        let expected = decode_hex(&format!(
+            "3059301306072a8648ce3d020106082a8648ce3d030107034200{POINT}"
+        ))
+        .unwrap();
+        assert_eq!(spki(&point), Some(expected.clone()));
+        // Most modules wrap CKA_EC_POINT in a DER OCTET STRING.
+        assert_eq!(spki(&[&[0x04, 0x41], &point[..]].concat()), Some(expected));
+        let mut compressed = point[..33].to_vec();
+        compressed[0] = 0x02;
+        assert_eq!(spki(&compressed), None);
+        assert_eq!(spki(&[&[0x03], &point[1..]].concat()), None);
+    }
+}
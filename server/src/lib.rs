@@ -0,0 +1,304 @@
+use std::fmt::Display;
+use std::io::{BufRead, Error as IoError, Write, stdin, stdout};
+
+use ic_auth_plugin_types::{
+    Action, AuthenticateRequest, AuthenticateResponse, AuthenticateResult, AuthnMode,
+    DescribeAuthnModeRequest, DescribeAuthnModeResponse, DescribeAuthnModeResult, ExtensionError,
+    ExtensionRequest, ExtensionResult, GetPublicKeyRequest, GetPublicKeyResult, Greeting,
+    KeySelectError, KeySelectRequest, KeySelectResult, ListSelectableKeysError,
+    ListSelectableKeysRequest, ListSelectableKeysResult, ProtocolState, ProtocolVersion,
+    ProtocolViolation, Request, SelectMode, SignArbitraryDataError, SignArbitraryDataRequest,
+    SignArbitraryDataResult, SignDelegationError, SignDelegationRequest, SignDelegationResult,
+    SignEnvelopesRequest, SignEnvelopesResult,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+pub use ic_auth_plugin_types as types;
+
+pub trait AuthPlugin {
+    fn select_mode(&self) -> SelectMode {
+        SelectMode::Unsupported
+    }
+
+    fn list_selectable_keys(
+        &mut self,
+        _req: ListSelectableKeysRequest,
+    ) -> ListSelectableKeysResult {
+        Err(ListSelectableKeysError::Unsupported)
+    }
+
+    fn select_key(&mut self, _req: KeySelectRequest<'_>) -> KeySelectResult {
+        Err(KeySelectError::Unsupported)
+    }
+
+    fn describe_authn_mode(&mut self, _req: DescribeAuthnModeRequest) -> DescribeAuthnModeResult {
+        Ok(DescribeAuthnModeResponse {
+            mode: AuthnMode::Automatic,
+            value: None,
+        })
+    }
+
+    fn authenticate(&mut self, _req: AuthenticateRequest<'_>) -> AuthenticateResult {
+        Ok(AuthenticateResponse {})
+    }
+
+    fn get_public_key(&mut self, req: GetPublicKeyRequest) -> GetPublicKeyResult<'_>;
+
+    fn sign_envelopes(&mut self, req: SignEnvelopesRequest<'_>) -> SignEnvelopesResult<'_>;
+
+    fn sign_delegation(&mut self, _req: SignDelegationRequest<'_>) -> SignDelegationResult<'_> {
+        Err(SignDelegationError::Unsupported)
+    }
+
+    fn sign_arbitrary_data(
+        &mut self,
+        _req: SignArbitraryDataRequest<'_>,
+    ) -> SignArbitraryDataResult<'_> {
+        Err(SignArbitraryDataError::Unsupported)
+    }
+
+    fn extensions(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    fn extension(&mut self, _req: ExtensionRequest<'_>) -> ExtensionResult {
+        Err(ExtensionError::unsupported())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("host I/O error: {0}")]
+    Io(#[from] IoError),
+    #[error("host encoding error: {0}")]
+    Encoding(#[from] serde_json::Error),
+    #[error("host violated the handshake process: {0}")]
+    Protocol(#[from] ProtocolViolation),
+    #[error("host sent a request for unsupported protocol version {0}")]
+    UnsupportedVersion(ProtocolVersion),
+}
+
+pub fn invoked_as_plugin() -> bool {
+    std::env::args()
+        .nth(1)
+        .is_some_and(|arg| arg == "--ic-auth-plugin")
+}
+
+pub fn run(plugin: impl AuthPlugin) -> Result<(), ServerError> {
+    serve(plugin, stdin().lock(), stdout().lock())
+}
+
+pub fn abort(message: impl Display) -> ! {
+    let greeting = Greeting {
+        v: vec![ProtocolVersion::V1],
+        select: None,
+        abort: Some(format!("{message}")),
+    };
+    let _ = send(&mut stdout().lock(), &greeting);
+    std::process::abort();
+}
+
+pub fn serve(
+    plugin: impl AuthPlugin,
+    mut input: impl BufRead,
+    mut output: impl Write,
+) -> Result<(), ServerError> {
+    let mut server = Server::new(plugin);
+    send(&mut output, &server.greeting())?;
+    let mut msg_buf = String::new();
+    loop {
+        msg_buf.clear();
+        if input.read_line(&mut msg_buf)? == 0 {
+            return Ok(());
+        }
+        let line = server.handle(&msg_buf)?;
+        writeln!(output, "{line}")?;
+        output.flush()?;
+    }
+}
+
+// The plugin side of a single connection, independent of how lines are exchanged with the host.
+// `serve` drives one over blocking I/O; plugins embedded in a host process (e.g. for testing) can
+// drive one over whatever transport they have.
+pub struct Server<P> {
+    plugin: P,
+    state: ProtocolState,
+}
+
+impl<P: AuthPlugin> Server<P> {
+    pub fn new(plugin: P) -> Self {
+        let state = ProtocolState::new(plugin.select_mode());
+        Self { plugin, state }
+    }
+
+    pub fn greeting(&self) -> Greeting {
+        let mut versions = vec![ProtocolVersion::V1];
+        versions.extend(
+            self.plugin
+                .extensions()
+                .into_iter()
+                .map(ProtocolVersion::Extension),
+        );
+        Greeting {
+            v: versions,
+            select: Some(self.state.select_mode()),
+            abort: None,
+        }
+    }
+
+    pub fn state(&self) -> &ProtocolState {
+        &self.state
+    }
+
+    pub fn plugin(&self) -> &P {
+        &self.plugin
+    }
+
+    pub fn plugin_mut(&mut self) -> &mut P {
+        &mut self.plugin
+    }
+
+    pub fn into_plugin(self) -> P {
+        self.plugin
+    }
+
+    // Handles one request line, returning the response line (without a trailing newline).
+    pub fn handle(&mut self, line: &str) -> Result<String, ServerError> {
+        let plugin = &mut self.plugin;
+        let state = &mut self.state;
+        let req = serde_json::from_str::<Value>(line)?;
+        let v = ProtocolVersion::deserialize(req.get("v").unwrap_or(&Value::Null))?;
+        if let Some(name) = v.extension_name() {
+            state.check(Action::Extension)?;
+            // Plugins only see requests for the extensions their greeting offered.
+            if !plugin.extensions().iter().any(|ext| ext == name) {
+                let res: ExtensionResult = Err(ExtensionError::unsupported());
+                return Ok(serde_json::to_string(&res)?);
+            }
+            let ext = ExtensionRequest::deserialize(req)?;
+            return Ok(serde_json::to_string(&plugin.extension(ext))?);
+        }
+        // The greeting only offers version 1, so the host cannot have negotiated anything else.
+        if v != ProtocolVersion::V1 {
+            return Err(ServerError::UnsupportedVersion(v));
+        }
+        let req = Request::deserialize(req)?;
+        let action = req.action();
+        state.check(action)?;
+        let line = match req {
+            Request::ListSelectableKeys(req) => {
+                serde_json::to_string(&plugin.list_selectable_keys(req))?
+            }
+            Request::KeySelect(req) => {
+                let res = plugin.select_key(req);
+                state.complete(action, res.is_ok());
+                serde_json::to_string(&res)?
+            }
+            Request::DescribeAuthnMode(req) => {
+                let res = plugin.describe_authn_mode(req);
+                state.complete(action, res.is_ok());
+                serde_json::to_string(&res)?
+            }
+            Request::Authenticate(req) => {
+                let res = plugin.authenticate(req);
+                state.complete(action, res.is_ok());
+                serde_json::to_string(&res)?
+            }
+            Request::GetPublicKey(req) => {
+                let res = plugin.get_public_key(req);
+                state.complete(action, res.is_ok());
+                serde_json::to_string(&res)?
+            }
+            Request::SignEnvelopes(req) => serde_json::to_string(&plugin.sign_envelopes(req))?,
+            Request::SignDelegation(req) => serde_json::to_string(&plugin.sign_delegation(req))?,
+            Request::SignArbitraryData(req) => {
+                serde_json::to_string(&plugin.sign_arbitrary_data(req))?
+            }
+        };
+        Ok(line)
+    }
+}
+
+fn send(output: &mut impl Write, msg: &impl Serialize) -> Result<(), ServerError> {
+    let line = serde_json::to_string(msg)?;
+    writeln!(output, "{line}")?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use ic_auth_plugin_types::{GetPublicKeyResponse, SignEnvelopesError};
+    use serde_json::json;
+
+    use super::*;
+
+    struct Plugin;
+
+    impl AuthPlugin for Plugin {
+        fn get_public_key(&mut self, _req: GetPublicKeyRequest) -> GetPublicKeyResult<'_> {
+            Ok(GetPublicKeyResponse {
+                public_key_der: Cow::Borrowed(&[1, 2, 3]),
+            })
+        }
+
+        fn sign_envelopes(&mut self, _req: SignEnvelopesRequest<'_>) -> SignEnvelopesResult<'_> {
+            Err(SignEnvelopesError::Refused)
+        }
+
+        fn extensions(&self) -> Vec<String> {
+            vec!["echo".into()]
+        }
+
+        fn extension(&mut self, req: ExtensionRequest<'_>) -> ExtensionResult {
+            Ok(req.payload)
+        }
+    }
+
+    fn handle(server: &mut Server<Plugin>, req: Value) -> Result<Value, ServerError> {
+        let line = server.handle(&req.to_string())?;
+        Ok(serde_json::from_str(&line).unwrap())
+    }
+
+    #[test]
+    fn handles_numbered_and_extension_requests() {
+        let mut server = Server::new(Plugin);
+        let resp = handle(&mut server, json!({ "v": 1, "action": "get-public-key" })).unwrap();
+        assert_eq!(resp, json!({ "Ok": { "public-key-der": "AQID" } }));
+        handle(&mut server, json!({ "v": 1, "action": "authenticate" })).unwrap();
+        let resp = handle(
+            &mut server,
+            json!({ "v": "#echo", "action": "ping", "payload": 7 }),
+        )
+        .unwrap();
+        assert_eq!(resp, json!({ "Ok": { "payload": 7 } }));
+    }
+
+    #[test]
+    fn rejects_unadvertised_extensions() {
+        let mut server = Server::new(Plugin);
+        handle(&mut server, json!({ "v": 1, "action": "authenticate" })).unwrap();
+        let resp = handle(
+            &mut server,
+            json!({ "v": "#other", "action": "ping", "payload": 7 }),
+        )
+        .unwrap();
+        assert_eq!(resp["Err"]["kind"], "unsupported");
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let mut server = Server::new(Plugin);
+        let err = handle(&mut server, json!({ "v": 2, "action": "get-public-key" })).unwrap_err();
+        assert!(matches!(
+            err,
+            ServerError::UnsupportedVersion(ProtocolVersion::Numbered(2))
+        ));
+        let err = handle(&mut server, json!({ "action": "get-public-key" })).unwrap_err();
+        assert!(matches!(err, ServerError::Encoding(_)));
+    }
+}
@@ -0,0 +1,107 @@
+// `Label` serializes as a hex string in human-readable formats, but deserializes as the bytes of
+// that string, so the paths of `read_state` contents would not survive the trip to the plugin (and
+// the plugin would sign a different request ID). The hex is decoded here instead.
+use ic_certification::Label;
+use ic_transport_types::EnvelopeContent;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+pub fn serialize<S: Serializer>(
+    contents: &impl AsRef<[EnvelopeContent]>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    contents.as_ref().serialize(serializer)
+}
+
+pub fn deserialize<'de, T: From<Vec<EnvelopeContent>>, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    let human_readable = deserializer.is_human_readable();
+    let mut contents = Vec::<EnvelopeContent>::deserialize(deserializer)?;
+    if human_readable {
+        for content in &mut contents {
+            if let EnvelopeContent::ReadState { paths, .. } = content {
+                for label in paths.iter_mut().flatten() {
+                    let bytes = decode_hex(label.as_bytes()).ok_or_else(|| {
+                        de::Error::custom("read_state path labels must be hex-encoded")
+                    })?;
+                    *label = Label::from(bytes);
+                }
+            }
+        }
+    }
+    Ok(contents.into())
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            let digits = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(digits, 16).ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use ic_certification::Label;
+    use ic_principal::Principal;
+    use ic_transport_types::EnvelopeContent;
+
+    use crate::{ProtocolVersion, SignEnvelopesRequest};
+
+    #[test]
+    fn read_state_request_id_survives_json() {
+        let request_id = [0xab; 32];
+        let contents = vec![
+            EnvelopeContent::ReadState {
+                ingress_expiry: 1_700_000_000_000_000_000,
+                sender: Principal::anonymous(),
+                paths: vec![vec![
+                    Label::from("request_status"),
+                    Label::from(request_id.to_vec()),
+                    Label::from("reply"),
+                ]],
+            },
+            EnvelopeContent::Call {
+                nonce: Some(vec![1, 2, 3]),
+                ingress_expiry: 1_700_000_000_000_000_000,
+                sender: Principal::anonymous(),
+                canister_id: Principal::management_canister(),
+                method_name: "greet".into(),
+                arg: vec![0x44, 0x49, 0x44, 0x4c, 0, 0],
+            },
+        ];
+        let req = SignEnvelopesRequest {
+            v: ProtocolVersion::V1,
+            contents: Cow::Borrowed(&contents),
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        let decoded: SignEnvelopesRequest = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.contents.len(), contents.len());
+        for (decoded, original) in decoded.contents.iter().zip(&contents) {
+            assert_eq!(decoded.to_request_id(), original.to_request_id());
+        }
+    }
+
+    #[test]
+    fn rejects_labels_that_are_not_hex() {
+        let contents = [EnvelopeContent::ReadState {
+            ingress_expiry: 1,
+            sender: Principal::anonymous(),
+            paths: vec![vec![Label::from("time")]],
+        }];
+        let req = SignEnvelopesRequest {
+            v: ProtocolVersion::V1,
+            contents: Cow::Borrowed(&contents),
+        };
+        let json = serde_json::to_string(&req).unwrap();
+        assert!(serde_json::from_str::<SignEnvelopesRequest>(&json).is_ok());
+        let json = json.replace("74696D65", "time");
+        assert!(serde_json::from_str::<SignEnvelopesRequest>(&json).is_err());
+    }
+}
@@ -6,18 +6,36 @@ use std::{
 use ic_principal::Principal;
 use ic_transport_types::EnvelopeContent;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use thiserror::Error;
 
 mod b64;
+mod contents;
+mod state;
+mod uint;
+mod version;
+
+pub use state::{Action, Phase, ProtocolState, ProtocolViolation};
+pub use version::ProtocolVersion;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct Greeting {
-    pub v: Vec<u32>,
+    pub v: Vec<ProtocolVersion>,
     pub select: Option<SelectMode>,
     pub abort: Option<String>,
 }
 
+impl Greeting {
+    pub fn supports(&self, version: &ProtocolVersion) -> bool {
+        self.v.contains(version)
+    }
+
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.v.iter().filter_map(ProtocolVersion::extension_name)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum SelectMode {
@@ -29,6 +47,8 @@ pub enum SelectMode {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "action", rename_all = "kebab-case")]
 pub enum Request<'a> {
+    // SPEC.md names the action `select-key`, against the type names.
+    #[serde(rename = "select-key")]
     KeySelect(KeySelectRequest<'a>),
     ListSelectableKeys(ListSelectableKeysRequest),
     GetPublicKey(GetPublicKeyRequest),
@@ -42,7 +62,7 @@ pub enum Request<'a> {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct KeySelectRequest<'a> {
-    pub v: u32,
+    pub v: ProtocolVersion,
     pub key: Cow<'a, str>,
 }
 
@@ -67,10 +87,10 @@ pub enum KeySelectError {
 
 pub type KeySelectResult = Result<KeySelectResponse, KeySelectError>;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct ListSelectableKeysRequest {
-    pub v: u32,
+    pub v: ProtocolVersion,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -95,10 +115,10 @@ pub enum ListSelectableKeysError {
 
 pub type ListSelectableKeysResult = Result<ListSelectableKeysResponse, ListSelectableKeysError>;
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct DescribeAuthnModeRequest {
-    pub v: u32,
+    pub v: ProtocolVersion,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -134,7 +154,7 @@ pub type DescribeAuthnModeResult = Result<DescribeAuthnModeResponse, DescribeAut
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct AuthenticateRequest<'a> {
-    pub v: u32,
+    pub v: ProtocolVersion,
     pub integrated: Option<AuthnMode>,
     pub value: Option<Cow<'a, str>>,
 }
@@ -160,10 +180,10 @@ pub enum AuthenticateError {
 
 pub type AuthenticateResult = Result<AuthenticateResponse, AuthenticateError>;
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct GetPublicKeyRequest {
-    pub v: u32,
+    pub v: ProtocolVersion,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -191,9 +211,10 @@ pub type GetPublicKeyResult<'a> = Result<GetPublicKeyResponse<'a>, GetPublicKeyE
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct SignDelegationRequest<'a> {
-    pub v: u32,
+    pub v: ProtocolVersion,
     #[serde(with = "b64")]
     pub public_key_der: Cow<'a, [u8]>,
+    #[serde(with = "uint")]
     pub desired_expiry: u128,
     pub desired_canisters: Option<Cow<'a, [Principal]>>,
 }
@@ -203,6 +224,7 @@ pub struct SignDelegationRequest<'a> {
 pub struct SignDelegationResponse<'a> {
     #[serde(with = "b64")]
     pub signature: Cow<'a, [u8]>,
+    #[serde(with = "uint")]
     pub expiry: u128,
 }
 
@@ -233,7 +255,8 @@ pub type SignDelegationResult<'a> = Result<SignDelegationResponse<'a>, SignDeleg
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct SignEnvelopesRequest<'a> {
-    pub v: u32,
+    pub v: ProtocolVersion,
+    #[serde(with = "contents")]
     pub contents: Cow<'a, [EnvelopeContent]>,
 }
 
@@ -267,7 +290,7 @@ pub type SignEnvelopesResult<'a> = Result<SignEnvelopesResponse<'a>, SignEnvelop
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct SignArbitraryDataRequest<'a> {
-    pub v: u32,
+    pub v: ProtocolVersion,
     #[serde(with = "b64")]
     pub data: Cow<'a, [u8]>,
 }
@@ -297,6 +320,35 @@ pub enum SignArbitraryDataError {
 pub type SignArbitraryDataResult<'a> =
     Result<SignArbitraryDataResponse<'a>, SignArbitraryDataError>;
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExtensionRequest<'a> {
+    pub v: ProtocolVersion,
+    pub action: Cow<'a, str>,
+    #[serde(flatten)]
+    pub payload: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Error)]
+#[error("{kind}{}", opt_prefix(": ", .message))]
+pub struct ExtensionError {
+    pub kind: String,
+    pub message: Option<String>,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+impl ExtensionError {
+    pub fn unsupported() -> Self {
+        Self {
+            kind: "unsupported".into(),
+            message: None,
+            fields: Map::new(),
+        }
+    }
+}
+
+pub type ExtensionResult = Result<Map<String, Value>, ExtensionError>;
+
 fn list<T: Display>(items: &[T]) -> String {
     let mut s = String::with_capacity(items.len() * 30);
     for (n, item) in items.iter().enumerate() {
@@ -0,0 +1,200 @@
+use std::fmt::{self, Display};
+
+use thiserror::Error;
+
+use crate::{Greeting, Request, SelectMode};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Action {
+    KeySelect,
+    ListSelectableKeys,
+    GetPublicKey,
+    DescribeAuthnMode,
+    Authenticate,
+    SignDelegation,
+    SignEnvelopes,
+    SignArbitraryData,
+    Extension,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::KeySelect => "select-key",
+            Self::ListSelectableKeys => "list-selectable-keys",
+            Self::GetPublicKey => "get-public-key",
+            Self::DescribeAuthnMode => "describe-authn-mode",
+            Self::Authenticate => "authenticate",
+            Self::SignDelegation => "sign-delegation",
+            Self::SignEnvelopes => "sign-envelopes",
+            Self::SignArbitraryData => "sign-arbitrary-data",
+            Self::Extension => "extension request",
+        }
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Request<'_> {
+    pub fn action(&self) -> Action {
+        match self {
+            Self::KeySelect(_) => Action::KeySelect,
+            Self::ListSelectableKeys(_) => Action::ListSelectableKeys,
+            Self::GetPublicKey(_) => Action::GetPublicKey,
+            Self::DescribeAuthnMode(_) => Action::DescribeAuthnMode,
+            Self::Authenticate(_) => Action::Authenticate,
+            Self::SignDelegation(_) => Action::SignDelegation,
+            Self::SignEnvelopes(_) => Action::SignEnvelopes,
+            Self::SignArbitraryData(_) => Action::SignArbitraryData,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Phase {
+    Greeted,
+    KeySelected,
+    Authenticated,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Error)]
+pub enum ProtocolViolation {
+    #[error("plugin requires a key to be selected before {0}")]
+    SelectRequired(Action),
+    #[error("key selection must precede all requests except list-selectable-keys")]
+    SelectTooLate,
+    #[error("{0} requires authentication first")]
+    NotAuthenticated(Action),
+    #[error("authentication has already been performed")]
+    AlreadyAuthenticated,
+}
+
+// Tracks the handshake phases described in SPEC.md. Hosts and plugins call `check` before sending
+// or handling a request, and `complete` once its result is known. Skipping key selection (when the
+// plugin does not require it) moves to `KeySelected` as soon as a post-selection request is made.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ProtocolState {
+    phase: Phase,
+    select_mode: SelectMode,
+}
+
+impl ProtocolState {
+    pub fn new(select_mode: SelectMode) -> Self {
+        Self {
+            phase: Phase::Greeted,
+            select_mode,
+        }
+    }
+
+    pub fn from_greeting(greeting: &Greeting) -> Self {
+        Self::new(greeting.select.unwrap_or(SelectMode::Unsupported))
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    pub fn select_mode(&self) -> SelectMode {
+        self.select_mode
+    }
+
+    pub fn check(&self, action: Action) -> Result<(), ProtocolViolation> {
+        let greeted = self.phase == Phase::Greeted;
+        let authenticated = self.phase == Phase::Authenticated;
+        match action {
+            Action::ListSelectableKeys => Ok(()),
+            Action::KeySelect if !greeted => Err(ProtocolViolation::SelectTooLate),
+            Action::KeySelect => Ok(()),
+            _ if greeted && self.select_mode == SelectMode::Required => {
+                Err(ProtocolViolation::SelectRequired(action))
+            }
+            Action::DescribeAuthnMode | Action::GetPublicKey => Ok(()),
+            Action::Authenticate if authenticated => Err(ProtocolViolation::AlreadyAuthenticated),
+            Action::Authenticate => Ok(()),
+            Action::SignDelegation
+            | Action::SignEnvelopes
+            | Action::SignArbitraryData
+            | Action::Extension
+                if !authenticated =>
+            {
+                Err(ProtocolViolation::NotAuthenticated(action))
+            }
+            Action::SignDelegation
+            | Action::SignEnvelopes
+            | Action::SignArbitraryData
+            | Action::Extension => Ok(()),
+        }
+    }
+
+    pub fn complete(&mut self, action: Action, succeeded: bool) {
+        match action {
+            Action::ListSelectableKeys => {}
+            Action::KeySelect if succeeded => self.phase = Phase::KeySelected,
+            Action::KeySelect => {}
+            Action::Authenticate if succeeded => self.phase = Phase::Authenticated,
+            _ if self.phase == Phase::Greeted => self.phase = Phase::KeySelected,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_handshake() {
+        let mut state = ProtocolState::new(SelectMode::Supported);
+        assert_eq!(state.check(Action::ListSelectableKeys), Ok(()));
+        assert_eq!(
+            state.check(Action::SignEnvelopes),
+            Err(ProtocolViolation::NotAuthenticated(Action::SignEnvelopes))
+        );
+        state.complete(Action::KeySelect, false);
+        assert_eq!(state.phase(), Phase::Greeted);
+        state.complete(Action::KeySelect, true);
+        assert_eq!(state.phase(), Phase::KeySelected);
+        assert_eq!(
+            state.check(Action::KeySelect),
+            Err(ProtocolViolation::SelectTooLate)
+        );
+        state.complete(Action::Authenticate, false);
+        assert_eq!(state.phase(), Phase::KeySelected);
+        state.complete(Action::Authenticate, true);
+        assert_eq!(state.phase(), Phase::Authenticated);
+        assert_eq!(
+            state.check(Action::Authenticate),
+            Err(ProtocolViolation::AlreadyAuthenticated)
+        );
+        assert_eq!(state.check(Action::Extension), Ok(()));
+    }
+
+    #[test]
+    fn skips_optional_selection() {
+        let mut state = ProtocolState::new(SelectMode::Unsupported);
+        assert_eq!(state.check(Action::DescribeAuthnMode), Ok(()));
+        state.complete(Action::DescribeAuthnMode, true);
+        assert_eq!(state.phase(), Phase::KeySelected);
+        assert_eq!(
+            state.check(Action::KeySelect),
+            Err(ProtocolViolation::SelectTooLate)
+        );
+    }
+
+    #[test]
+    fn enforces_required_selection() {
+        let mut state = ProtocolState::new(SelectMode::Required);
+        for action in [Action::GetPublicKey, Action::Authenticate] {
+            assert_eq!(
+                state.check(action),
+                Err(ProtocolViolation::SelectRequired(action))
+            );
+        }
+        state.complete(Action::KeySelect, true);
+        assert_eq!(state.check(Action::Authenticate), Ok(()));
+    }
+}
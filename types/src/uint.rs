@@ -0,0 +1,77 @@
+// serde's buffering of internally tagged enums (like `Request`) cannot deserialize u128 directly, so
+// expiries are read through `deserialize_any`, which also accepts them as u64.
+use serde::{
+    Deserializer, Serializer,
+    de::{self, Unexpected, Visitor},
+    ser,
+};
+use std::fmt;
+
+// JSON parsers generally cannot read integers beyond u64 (serde_json reads them as floats), and no
+// nanosecond timestamp needs one, so larger values are refused rather than sent.
+pub fn serialize<S: Serializer>(n: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    let n = u64::try_from(*n).map_err(|_| ser::Error::custom(format!("{n} exceeds u64")))?;
+    serializer.serialize_u64(n)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    struct U128Visitor;
+    impl Visitor<'_> for U128Visitor {
+        type Value = u128;
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a non-negative integer")
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v.into())
+        }
+
+        fn visit_u128<E>(self, v: u128) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v)
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            u128::try_from(v).map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+        }
+    }
+    deserializer.deserialize_any(U128Visitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Expiry {
+        #[serde(with = "super")]
+        expiry: u128,
+    }
+
+    #[test]
+    fn round_trips_u64_range() {
+        let expiry = Expiry {
+            expiry: u64::MAX.into(),
+        };
+        let json = serde_json::to_string(&expiry).unwrap();
+        assert_eq!(json, format!("{{\"expiry\":{}}}", u64::MAX));
+        assert_eq!(serde_json::from_str::<Expiry>(&json).unwrap(), expiry);
+        assert!(serde_json::from_str::<Expiry>(r#"{"expiry":-1}"#).is_err());
+    }
+
+    #[test]
+    fn refuses_to_serialize_beyond_u64() {
+        let expiry = Expiry {
+            expiry: u128::from(u64::MAX) + 1,
+        };
+        assert!(serde_json::to_string(&expiry).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt::{self, Display};
+
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, Unexpected, Visitor},
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ProtocolVersion {
+    Numbered(u32),
+    // Stored without the leading `#`.
+    Extension(String),
+}
+
+impl ProtocolVersion {
+    pub const V1: Self = Self::Numbered(1);
+
+    pub fn extension(name: impl Into<String>) -> Self {
+        Self::Extension(name.into())
+    }
+
+    pub fn extension_name(&self) -> Option<&str> {
+        match self {
+            Self::Extension(name) => Some(name),
+            Self::Numbered(_) => None,
+        }
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Numbered(n) => write!(f, "{n}"),
+            Self::Extension(name) => write!(f, "#{name}"),
+        }
+    }
+}
+
+impl Serialize for ProtocolVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Numbered(n) => serializer.serialize_u32(*n),
+            Self::Extension(_) => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct VersionVisitor;
+        impl Visitor<'_> for VersionVisitor {
+            type Value = ProtocolVersion;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a version number or a string starting with '#'")
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                u32::try_from(v)
+                    .map(ProtocolVersion::Numbered)
+                    .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                u32::try_from(v)
+                    .map(ProtocolVersion::Numbered)
+                    .map_err(|_| E::invalid_value(Unexpected::Signed(v), &self))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                match v.strip_prefix('#') {
+                    Some(name) => Ok(ProtocolVersion::Extension(name.to_string())),
+                    None => Err(E::invalid_value(Unexpected::Str(v), &self)),
+                }
+            }
+        }
+        deserializer.deserialize_any(VersionVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_numbers_and_extensions() {
+        let versions = vec![ProtocolVersion::V1, ProtocolVersion::extension("echo")];
+        let json = serde_json::to_string(&versions).unwrap();
+        assert_eq!(json, r##"[1,"#echo"]"##);
+        assert_eq!(
+            serde_json::from_str::<Vec<ProtocolVersion>>(&json).unwrap(),
+            versions
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_versions() {
+        for json in [r#""1""#, "-1", "4294967296", "1.5"] {
+            assert!(
+                serde_json::from_str::<ProtocolVersion>(json).is_err(),
+                "{json} was accepted"
+            );
+        }
+    }
+}